@@ -1,25 +1,319 @@
+use argh::FromArgs;
+use tide::listener::Listener;
 use tide::prelude::*;
 use tide::{Request, StatusCode};
 
 trait SaveLoad {
     fn load(&mut self);
     fn save(&self);
+    /// Whether the backing file or database can still be read, for /healthz.
+    fn check(&self) -> Result<(), String>;
 }
 trait RegisterFind {
-    fn register_twist_thread(&mut self, cfg: TwistOnConfigure);
+    /// Saves the integration, returning true when it is a new one rather
+    /// than a repeated configure of a stored one.
+    fn register_twist_thread(&mut self, cfg: TwistOnConfigure) -> Result<bool, String>;
     fn find_twist_thread(&self, secret_id: String) -> Option<TwistIntegration>;
-    fn unregister_twist_thread(self: &mut Self, install_id: String);
+    fn list_twist_threads(&self) -> Vec<TwistIntegration>;
+    fn replace_twist_threads(&mut self, twists: Vec<TwistIntegration>);
+    fn unregister_twist_thread(self: &mut Self, install_id: String) -> bool;
+    fn update_twist_thread(
+        &mut self,
+        secret_id: &str,
+        update: &mut dyn FnMut(&mut TwistIntegration),
+    ) -> bool;
 }
 
 struct FileStore {
     path: String,
     twist_integrations: std::vec::Vec<TwistIntegration>,
+    max_integrations: Option<usize>,
+    /// start empty when the file is missing rather than bail out, only serve
+    /// does so as elsewhere a missing file is most likely a mistyped path
+    create_if_missing: bool,
+    /// encrypts the sensitive fields on disk when set
+    key: Option<DbKey>,
+    /// largest file, in bytes, `load` reads
+    max_size: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TwistIntegration {
     secret_id: String,
     configuration: TwistOnConfigure,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth_token: Option<Secret>,
+    #[serde(default)]
+    notify_all_on_open: bool,
+    /// thread urls keyed by lowercased severity, alerts of other severities go
+    /// to the configured post_data_url
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    severity_routes: std::collections::HashMap<String, String>,
+    /// thread urls for the policies matching a regex, the first match winning
+    /// over any severity route
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    policy_routes: Vec<PolicyRoute>,
+    /// epoch seconds until which alerts are not posted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mute_until: Option<u64>,
+    /// alerts are not posted until unmuted, see the mute subcommand
+    #[serde(default)]
+    muted: bool,
+    /// epoch seconds of registration, unknown for older entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+    /// regexes whose matches are replaced with `[redacted]` before posting
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    redact_patterns: Vec<RedactPattern>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    maintenance_windows: Vec<MaintenanceWindow>,
+    #[serde(default)]
+    include_chart: bool,
+    /// post open incidents with a checklist responders can tick off
+    #[serde(default)]
+    task_checklist: bool,
+    /// what to do with messages over the content limit
+    #[serde(default)]
+    oversize_behavior: OversizeBehavior,
+    /// fields identifying an incident across its notifications, for alert
+    /// types whose url changes between open and resolve, see
+    /// `GoogleWebhookPayload::fingerprint`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fingerprint: Vec<String>,
+    /// replaces the `--footer-template-file`, empty for no footer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    footer: Option<String>,
+    /// `{runbook_url}` of the footer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    runbook_url: Option<String>,
+    /// token twist sends along its outgoing webhooks for this integration,
+    /// unknown for entries configured before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verify_token: Option<Secret>,
+}
+
+impl TwistIntegration {
+    pub fn new(cfg: TwistOnConfigure) -> Self {
+        Self {
+            verify_token: cfg.verify_token.clone().map(Secret),
+            secret_id: cfg.install_id.clone(),
+            configuration: cfg,
+            auth_token: None,
+            notify_all_on_open: false,
+            severity_routes: std::collections::HashMap::new(),
+            policy_routes: Vec::new(),
+            mute_until: None,
+            muted: false,
+            created_at: Some(unix_now()),
+            redact_patterns: Vec::new(),
+            maintenance_windows: Vec::new(),
+            include_chart: false,
+            task_checklist: false,
+            oversize_behavior: OversizeBehavior::Truncate,
+            fingerprint: Vec::new(),
+            footer: None,
+            runbook_url: None,
+        }
+    }
+
+    /// The thread url an alert of the given severity is posted to.
+    fn post_url_for(&self, severity: Option<&str>, policy_name: Option<&str>) -> &str {
+        let by_policy = policy_name.and_then(|policy_name| {
+            self.policy_routes
+                .iter()
+                .find(|route| route.policy.0.is_match(policy_name))
+        });
+        match by_policy {
+            Some(route) => &route.post_data_url,
+            None => severity
+                .and_then(|severity| self.severity_routes.get(&severity.to_lowercase()))
+                .unwrap_or(&self.configuration.post_data_url),
+        }
+    }
+
+    /// Thread urls an alert may be posted to, the configured one included.
+    fn targets(&self) -> usize {
+        1 + self.severity_routes.len() + self.policy_routes.len()
+    }
+}
+
+/// Sends the alerts of policies whose name matches `policy` to another thread.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PolicyRoute {
+    policy: PolicyPattern,
+    post_data_url: String,
+}
+
+/// A policy name regex, compiled once as the route is set or loaded, which
+/// also keeps invalid ones out of the store.
+#[derive(Debug, Clone)]
+struct PolicyPattern(regex::Regex);
+
+impl Serialize for PolicyPattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for PolicyPattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        regex::Regex::new(&pattern)
+            .map(PolicyPattern)
+            .map_err(|err| {
+                serde::de::Error::custom(format!("invalid policy route {:?}: {}", pattern, err))
+            })
+    }
+}
+
+/// A redaction regex of an integration, compiled once as it is set or loaded
+/// rather than for every alert, like `PolicyPattern`.
+#[derive(Debug, Clone)]
+struct RedactPattern(regex::Regex);
+
+impl Serialize for RedactPattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for RedactPattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        regex::Regex::new(&pattern)
+            .map(RedactPattern)
+            .map_err(|err| {
+                serde::de::Error::custom(format!(
+                    "invalid redaction pattern {:?}: {}",
+                    pattern, err
+                ))
+            })
+    }
+}
+
+/// Key the sensitive fields of the store are encrypted with at rest, a
+/// base64 encoded AES-256-GCM key given through `--db-key` or `--db-key-file`.
+#[derive(Clone)]
+struct DbKey(aes_gcm::Key<aes_gcm::Aes256Gcm>);
+
+/// Marks a store field as encrypted, so plaintext from before a key was
+/// configured still loads.
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+impl DbKey {
+    fn from_base64(encoded: &str) -> Result<Self, String> {
+        use base64::Engine as _;
+
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .map_err(|err| format!("invalid db key: {}", err))?;
+        if bytes.len() != 32 {
+            return Err(format!("db key must be 32 bytes, got {}", bytes.len()));
+        }
+        Ok(DbKey(*aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(
+            &bytes,
+        )))
+    }
+
+    fn from_options(
+        db_key: Option<&str>,
+        db_key_file: Option<&str>,
+    ) -> Result<Option<Self>, String> {
+        let encoded = match (db_key, db_key_file) {
+            (Some(_), Some(_)) => return Err("only one of --db-key and --db-key-file".into()),
+            (Some(key), None) => key.to_string(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .map_err(|err| format!("failed to read db key {}: {}", path, err))?,
+            (None, None) => return Ok(None),
+        };
+        Self::from_base64(&encoded).map(Some)
+    }
+
+    fn encrypt(&self, plain: &str) -> Result<String, String> {
+        use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+        use base64::Engine as _;
+
+        let cipher = aes_gcm::Aes256Gcm::new(&self.0);
+        let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = cipher
+            .encrypt(&nonce, plain.as_bytes())
+            .map_err(|_| "failed to encrypt store field".to_string())?;
+        let mut data = nonce.to_vec();
+        data.extend(sealed);
+        Ok(format!(
+            "{}{}",
+            ENCRYPTED_PREFIX,
+            base64::engine::general_purpose::STANDARD.encode(data)
+        ))
+    }
+
+    fn decrypt(&self, stored: &str) -> Result<String, String> {
+        use aes_gcm::aead::{Aead, KeyInit};
+        use base64::Engine as _;
+
+        let encoded = match stored.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(encoded) => encoded,
+            None => return Ok(stored.to_string()),
+        };
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|err| format!("malformed encrypted field: {}", err))?;
+        if data.len() < 12 {
+            return Err("malformed encrypted field".into());
+        }
+        let (nonce, sealed) = data.split_at(12);
+        let plain = aes_gcm::Aes256Gcm::new(&self.0)
+            .decrypt(aes_gcm::Nonce::from_slice(nonce), sealed)
+            .map_err(|_| "wrong db key or corrupt field".to_string())?;
+        String::from_utf8(plain).map_err(|err| err.to_string())
+    }
+}
+
+/// Rewrites, with `f`, every field of a stored integration that holds a
+/// credential: the tokens and the thread urls, which embed twist's token.
+fn for_each_sensitive(
+    entry: &mut serde_json::Value,
+    f: &mut dyn FnMut(&str) -> Result<String, String>,
+) -> Result<(), String> {
+    let mut rewrite = |value: &mut serde_json::Value| -> Result<(), String> {
+        if let serde_json::Value::String(text) = value {
+            *text = f(text)?;
+        }
+        Ok(())
+    };
+    if let Some(token) = entry.get_mut("auth_token") {
+        rewrite(token)?;
+    }
+    if let Some(token) = entry.get_mut("verify_token") {
+        rewrite(token)?;
+    }
+    if let Some(url) = entry.pointer_mut("/configuration/post_data_url") {
+        rewrite(url)?;
+    }
+    if let Some(serde_json::Value::Object(routes)) = entry.get_mut("severity_routes") {
+        for url in routes.values_mut() {
+            rewrite(url)?;
+        }
+    }
+    if let Some(serde_json::Value::Array(routes)) = entry.get_mut("policy_routes") {
+        for route in routes {
+            if let Some(url) = route.get_mut("post_data_url") {
+                rewrite(url)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A credential that is stored as a plain string but never shows up in logs.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+struct Secret(String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
 }
 
 impl FileStore {
@@ -27,31 +321,147 @@ impl FileStore {
         Self {
             path: path.to_string(),
             twist_integrations: std::vec::Vec::new(),
+            max_integrations: None,
+            create_if_missing: false,
+            key: None,
+            max_size: None,
+        }
+    }
+
+    /// Moves an unparseable store out of the way and starts empty, keeping
+    /// the old file around to recover integrations from by hand.
+    fn set_aside(&mut self, err: &str) {
+        let backup = format!("{}.corrupt-{}", self.path, unix_now());
+        tide::log::error!("the store at {} is corrupt: {}", self.path, err);
+        match std::fs::rename(&self.path, &backup) {
+            Ok(()) => tide::log::error!("moved it to {}, starting empty", backup),
+            Err(err) => {
+                tide::log::error!("failed to move it to {}: {}", backup, err);
+                std::process::exit(1);
+            }
         }
+        self.twist_integrations = Vec::new();
     }
 }
 
 impl SaveLoad for FileStore {
     fn load(self: &mut Self) {
-        let data = std::fs::read_to_string(&self.path).unwrap();
-        self.twist_integrations = serde_json::from_str(data.as_str()).unwrap();
+        if let (Some(max), Ok(meta)) = (self.max_size, std::fs::metadata(&self.path)) {
+            if meta.len() > max {
+                eprintln!(
+                    "refusing to load the store at {}: {} bytes exceeds --max-db-size of {}",
+                    self.path,
+                    meta.len(),
+                    max
+                );
+                std::process::exit(1);
+            }
+        }
+        let data = match std::fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && self.create_if_missing => {
+                tide::log::info!("no store at {}, starting empty", self.path);
+                self.twist_integrations = Vec::new();
+                return;
+            }
+            Err(err) => {
+                eprintln!("failed to read the store at {}: {}", self.path, err);
+                std::process::exit(1);
+            }
+        };
+        if data.trim().is_empty() && self.create_if_missing {
+            tide::log::info!("empty store at {}, starting empty", self.path);
+            self.twist_integrations = Vec::new();
+            return;
+        }
+        let mut entries: Vec<serde_json::Value> = match serde_json::from_str(data.as_str()) {
+            Ok(entries) => entries,
+            Err(err) if self.create_if_missing => {
+                self.set_aside(&err.to_string());
+                return;
+            }
+            Err(err) => {
+                eprintln!("the store at {} is corrupt: {}", self.path, err);
+                std::process::exit(1);
+            }
+        };
+        if let Some(key) = &self.key {
+            for entry in &mut entries {
+                if let Err(err) = for_each_sensitive(entry, &mut |plain| key.decrypt(plain)) {
+                    eprintln!("failed to decrypt the store at {}: {}", self.path, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        self.twist_integrations = match serde_json::from_value(serde_json::Value::Array(entries)) {
+            Ok(twists) => twists,
+            Err(err) => {
+                eprintln!("invalid integration in {}: {}", self.path, err);
+                std::process::exit(1);
+            }
+        };
     }
 
     fn save(self: &Self) {
-        let data = serde_json::to_string(&self.twist_integrations).unwrap();
-        std::fs::write(&self.path, data).unwrap();
+        let mut value = serde_json::to_value(&self.twist_integrations).unwrap();
+        if let (Some(key), serde_json::Value::Array(entries)) = (&self.key, &mut value) {
+            for entry in entries {
+                for_each_sensitive(entry, &mut |plain| key.encrypt(plain)).unwrap();
+            }
+        }
+        write_atomically(&self.path, &value.to_string()).unwrap();
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match std::fs::File::open(&self.path) {
+            Ok(_) => Ok(()),
+            // not written until the first integration is configured
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound
+                    && self.twist_integrations.is_empty() =>
+            {
+                Ok(())
+            }
+            Err(err) => Err(format!("{} is unreadable: {}", self.path, err)),
+        }
     }
 }
+
+/// Writes `data` next to `path` and renames it over it, so a crash mid-write
+/// leaves the previous file intact.
+fn write_atomically(path: &str, data: &str) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
+
 impl RegisterFind for FileStore {
-    fn register_twist_thread(self: &mut Self, cfg: TwistOnConfigure) {
-        self.twist_integrations.push(TwistIntegration {
-            secret_id: cfg.install_id.clone(),
-            configuration: cfg,
-        });
+    fn register_twist_thread(self: &mut Self, cfg: TwistOnConfigure) -> Result<bool, String> {
+        // a reinstall, or twist resending the configure, updates the entry in
+        // place and keeps its settings
+        if let Some(twist) = self
+            .twist_integrations
+            .iter_mut()
+            .find(|x| x.secret_id == cfg.install_id)
+        {
+            if let Some(token) = &cfg.verify_token {
+                twist.verify_token = Some(Secret(token.clone()));
+            }
+            twist.configuration = cfg;
+            self.save();
+            return Ok(false);
+        }
+        if let Some(max) = self.max_integrations {
+            if self.twist_integrations.len() >= max {
+                return Err(format!("integration limit of {} reached", max));
+            }
+        }
+        self.twist_integrations.push(TwistIntegration::new(cfg));
         self.save();
+        Ok(true)
     }
 
-    fn unregister_twist_thread(self: &mut Self, install_id: String) {
+    fn unregister_twist_thread(self: &mut Self, install_id: String) -> bool {
         if let Some(idx) = self
             .twist_integrations
             .iter()
@@ -59,6 +469,27 @@ impl RegisterFind for FileStore {
         {
             self.twist_integrations.remove(idx);
             self.save();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn update_twist_thread(
+        &mut self,
+        secret_id: &str,
+        update: &mut dyn FnMut(&mut TwistIntegration),
+    ) -> bool {
+        if let Some(twist) = self
+            .twist_integrations
+            .iter_mut()
+            .find(|x| x.secret_id == secret_id)
+        {
+            update(twist);
+            self.save();
+            true
+        } else {
+            false
         }
     }
 
@@ -73,270 +504,6726 @@ impl RegisterFind for FileStore {
             None
         }
     }
-}
-impl ApplicationStore for FileStore {}
-
-trait ApplicationStore: Send + SaveLoad + RegisterFind {}
 
-#[derive(Clone)]
-struct State {
-    server_name: String,
-    store: std::sync::Arc<std::sync::Mutex<Box<dyn ApplicationStore>>>,
-}
+    fn list_twist_threads(&self) -> Vec<TwistIntegration> {
+        self.twist_integrations.clone()
+    }
 
-impl State {
-    pub fn new(name: &str, store: Box<dyn ApplicationStore>) -> Self {
-        Self {
-            server_name: name.to_string(),
-            store: std::sync::Arc::new(std::sync::Mutex::new(store)),
-        }
+    fn replace_twist_threads(&mut self, twists: Vec<TwistIntegration>) {
+        self.twist_integrations = twists;
+        self.save();
     }
 }
+impl ApplicationStore for FileStore {}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TwistOnConfigure {
-    install_id: String,
-    post_data_url: String,
-    user_id: String,
-    user_name: String,
+/// Keeps each integration as a json row of a SQLite database, written as it
+/// changes rather than rewriting the whole store like `FileStore`, and safe to
+/// share between processes.
+struct SqliteStore {
+    path: String,
+    conn: std::sync::Mutex<rusqlite::Connection>,
+    max_integrations: Option<usize>,
+    /// encrypts the sensitive fields of each row when set
+    key: Option<DbKey>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
-enum GoogleWebhookPayload {
-    GoogleLogAlert(GoogleLogAlert),
-    GoogleUptimeAlert(GoogleUptimeAlert),
-}
+impl SqliteStore {
+    fn open(path: &str) -> Result<Self, String> {
+        let conn = rusqlite::Connection::open(path)
+            .map_err(|err| format!("failed to open {}: {}", path, err))?;
+        Ok(Self {
+            path: path.to_string(),
+            conn: std::sync::Mutex::new(conn),
+            max_integrations: None,
+            key: None,
+        })
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GoogleUptimeAlert {
-    incident: GoogleUptimeIncident,
-}
+    fn to_row(&self, twist: &TwistIntegration) -> String {
+        let mut value = serde_json::to_value(twist).unwrap();
+        if let Some(key) = &self.key {
+            for_each_sensitive(&mut value, &mut |plain| key.encrypt(plain)).unwrap();
+        }
+        value.to_string()
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GoogleUptimeIncident {
-    policy_name: String,
-    url: String,
-    summary: String,
-    state: String,
-}
+    fn decode_row(&self, data: &str) -> Option<TwistIntegration> {
+        let parsed = serde_json::from_str(data).map_err(|err| err.to_string());
+        let parsed = parsed.and_then(|mut value: serde_json::Value| {
+            if let Some(key) = &self.key {
+                for_each_sensitive(&mut value, &mut |stored| key.decrypt(stored))?;
+            }
+            serde_json::from_value(value).map_err(|err| err.to_string())
+        });
+        match parsed {
+            Ok(twist) => Some(twist),
+            Err(err) => {
+                tide::log::error!("skipping invalid integration in {}: {}", self.path, err);
+                None
+            }
+        }
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GoogleLogAlert {
-    incident: GoogleLogIncident,
-}
+    /// Every row as stored, in insertion order.
+    fn rows(&self) -> Vec<String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT data FROM integrations ORDER BY rowid")
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(Result::ok)
+            .collect();
+        rows
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GoogleLogIncident {
-    documentation: AlertDocumentation,
-    policy_name: String,
-    resource: GoogleResource,
-    url: String,
+    fn write(&self, twist: &TwistIntegration) {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO integrations (secret_id, data) VALUES (?1, ?2)",
+                rusqlite::params![twist.secret_id, self.to_row(twist)],
+            )
+            .unwrap();
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GoogleResource {
-    labels: serde_json::Value,
-    #[serde(rename = "type")]
-    resource_type: String,
-}
+impl SaveLoad for SqliteStore {
+    fn load(&mut self) {
+        let created = self.conn.lock().unwrap().execute(
+            "CREATE TABLE IF NOT EXISTS integrations (secret_id TEXT PRIMARY KEY, data TEXT NOT NULL)",
+            [],
+        );
+        if let Err(err) = created {
+            eprintln!("failed to set up the store at {}: {}", self.path, err);
+            std::process::exit(1);
+        }
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AlertDocumentation {
-    content: String,
-    mime_type: String,
-}
+    /// Every change is written as it is made.
+    fn save(&self) {}
 
-#[async_std::main]
-async fn main() -> tide::Result<()> {
-    // let data = async_std::fs::read_to_string("uptime.json").await?;
-    // if let Some(reply) = reply_to_json(data) {
-    //     println!("{}", reply);
-    // }
-    // return Ok(());
+    fn check(&self) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT count(*) FROM integrations", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|_| ())
+            .map_err(|err| format!("{} is unreadable: {}", self.path, err))
+    }
+}
 
-    tide::log::start();
+impl RegisterFind for SqliteStore {
+    fn register_twist_thread(&mut self, cfg: TwistOnConfigure) -> Result<bool, String> {
+        // as with `FileStore`, a repeated configure updates the entry in place
+        if let Some(mut twist) = self.find_twist_thread(cfg.install_id.clone()) {
+            if let Some(token) = &cfg.verify_token {
+                twist.verify_token = Some(Secret(token.clone()));
+            }
+            twist.configuration = cfg;
+            self.write(&twist);
+            return Ok(false);
+        }
+        if let Some(max) = self.max_integrations {
+            if self.list_twist_threads().len() >= max {
+                return Err(format!("integration limit of {} reached", max));
+            }
+        }
+        self.write(&TwistIntegration::new(cfg));
+        Ok(true)
+    }
 
-    let mut file = FileStore::new("db.json");
-    file.load();
-    file.twist_integrations
-        .iter()
-        .for_each(|x| tide::log::info!("> {} {}", x.secret_id, x.configuration.user_name));
-    let state = State::new("tuta.smeten.se", Box::new(file));
+    fn find_twist_thread(&self, secret_id: String) -> Option<TwistIntegration> {
+        let data: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM integrations WHERE secret_id = ?1",
+                [&secret_id],
+                |row| row.get(0),
+            )
+            .ok();
+        data.and_then(|data| self.decode_row(&data))
+    }
 
-    let mut app = tide::with_state(state);
+    fn list_twist_threads(&self) -> Vec<TwistIntegration> {
+        self.rows()
+            .iter()
+            .filter_map(|data| self.decode_row(data))
+            .collect()
+    }
 
-    app.with(tide::utils::After(|mut res: tide::Response| async {
-        if let Some(err) = res.error() {
-            res.set_body(err.to_string());
-            res.set_status(500);
+    fn replace_twist_threads(&mut self, twists: Vec<TwistIntegration>) {
+        let rows: Vec<(String, String)> = twists
+            .iter()
+            .map(|twist| (twist.secret_id.clone(), self.to_row(twist)))
+            .collect();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().unwrap();
+        tx.execute("DELETE FROM integrations", []).unwrap();
+        for (secret_id, data) in &rows {
+            tx.execute(
+                "INSERT OR REPLACE INTO integrations (secret_id, data) VALUES (?1, ?2)",
+                rusqlite::params![secret_id, data],
+            )
+            .unwrap();
         }
-        Ok(res)
-    }));
-
-    app.at("/twist/on_configure").get(twist_configure);
-    app.at("/twist/outgoing").post(twist_outgoing);
-    app.at("/gcp/webhooks/:id").post(gcp_webhook);
-    app.listen("0.0.0.0:9999").await?;
-
-    tide::log::info!("byee!");
+        tx.commit().unwrap();
+    }
 
-    Ok(())
-}
+    fn unregister_twist_thread(&mut self, install_id: String) -> bool {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM integrations WHERE secret_id = ?1",
+                [&install_id],
+            )
+            .unwrap()
+            > 0
+    }
 
-fn reply_to_json(json: String) -> Option<String> {
-    match serde_json::from_str::<GoogleWebhookPayload>(&json) {
-        Ok(payload) => match payload {
-            GoogleWebhookPayload::GoogleLogAlert(alert) => {
-                let svc = alert
-                    .incident
-                    .resource
-                    .labels
-                    .as_object()
-                    .and_then(|labels| labels.get("container_name"))
-                    .and_then(|name_val| name_val.as_str())
-                    .map_or("unknown", |name| name);
-
-                Some(format!(
-                    "🚨 {alert} on {name} [incident]({incident_url})\n\n{docs}",
-                    alert = alert.incident.policy_name,
-                    name = svc,
-                    incident_url = alert.incident.url,
-                    docs = alert.incident.documentation.content,
-                ))
+    fn update_twist_thread(
+        &mut self,
+        secret_id: &str,
+        update: &mut dyn FnMut(&mut TwistIntegration),
+    ) -> bool {
+        match self.find_twist_thread(secret_id.to_string()) {
+            Some(mut twist) => {
+                update(&mut twist);
+                self.write(&twist);
+                true
             }
-            GoogleWebhookPayload::GoogleUptimeAlert(alert) => Some(format!(
-                "{state} {alert} [incident]({incident_url})\n\n{summary}",
-                alert = alert.incident.policy_name,
-                incident_url = alert.incident.url,
-                summary = alert.incident.summary,
-                state = if alert.incident.state == "open" {
-                    "🚨"
-                } else {
-                    "✅"
-                },
-            )),
-        },
-        Err(err) => Some(format!(
-            "Failed to parse due to {error}:\n\n```\n{payload}\n```",
-            error = err,
-            payload = json.to_string()
-        )),
+            None => false,
+        }
     }
 }
+impl ApplicationStore for SqliteStore {}
 
-async fn twist_content(req: &mut Request<State>) -> Option<String> {
-    match req.body_string().await {
-        Ok(json) => reply_to_json(json),
-        Err(_) => None,
-    }
+trait ApplicationStore: Send + Sync + SaveLoad + RegisterFind {}
+
+/// The `--db`, `--store`, `--db-key` and `--db-key-file` options of every
+/// subcommand working on the integrations.
+struct StoreArgs<'a> {
+    db: &'a str,
+    store: Option<&'a str>,
+    db_key: Option<&'a str>,
+    db_key_file: Option<&'a str>,
 }
 
-async fn gcp_webhook(mut req: Request<State>) -> tide::Result {
-    match twist_content(&mut req).await {
-        Some(reply) => {
-            let webhook_id = req.param("id")?;
-            let store = req.state().store.lock().unwrap();
-            if let Some(twist) = store.find_twist_thread(webhook_id.to_string()) {
-                reqwest::blocking::Client::new()
-                    .request(reqwest::Method::POST, twist.configuration.post_data_url)
-                    .body(serde_json::to_string(&json!({
-                        "content": reply,
-                    }))?)
-                    .header("Content-Type", "application/json")
-                    .send()
-                    .unwrap();
-            } else {
-                tide::log::warn!("no twist integration found with id {}", webhook_id);
-            }
+impl<'a> StoreArgs<'a> {
+    fn new(
+        db: &'a str,
+        store: &'a Option<String>,
+        db_key: &'a Option<String>,
+        db_key_file: &'a Option<String>,
+    ) -> Self {
+        Self {
+            db,
+            store: store.as_deref(),
+            db_key: db_key.as_deref(),
+            db_key_file: db_key_file.as_deref(),
         }
-        None => {}
-    };
+    }
 
-    Ok("OK".into())
-}
+    /// How the store is referred to in messages.
+    fn name(&self) -> &str {
+        self.store.unwrap_or(self.db)
+    }
 
-async fn twist_outgoing(mut req: Request<State>) -> tide::Result {
-    #[derive(Debug, Deserialize)]
-    struct Outgoing {
-        event_type: String, // message, thread, comment, uninstall, ping
-        user_id: String,
-        user_name: String,
+    fn url(&self) -> String {
+        self.store
+            .map_or_else(|| format!("file://{}", self.db), str::to_string)
+    }
 
-        // only on message, thread or comment
-        content: Option<String>,
+    fn key(&self) -> Option<DbKey> {
+        match DbKey::from_options(self.db_key, self.db_key_file) {
+            Ok(key) => key,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(2);
+            }
+        }
+    }
 
-        // only when event_type = uninstall
-        install_id: Option<String>,
+    /// Where a `serve` of a json store writes its pid. That store is read at
+    /// startup and saved whole, so a bridge serving it would revert changes
+    /// made to the file beside it.
+    fn pidfile(&self) -> Option<String> {
+        self.url()
+            .strip_prefix("file://")
+            .map(|path| format!("{}.pid", path))
     }
 
-    #[derive(Debug, Serialize)]
-    struct Reply {
-        content: String,
+    /// The pid of the running bridge serving the store, if any.
+    fn served_by(&self) -> Option<u32> {
+        let pid: u32 = std::fs::read_to_string(self.pidfile()?)
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        // a bridge that did not get to clean up leaves a stale pidfile
+        std::path::Path::new(&format!("/proc/{}", pid))
+            .exists()
+            .then_some(pid)
     }
 
-    let x: Outgoing = req.body_json().await?;
-    let mut state = req.state().store.lock().unwrap();
+    /// Exits when a running bridge serves the store, see `pidfile`.
+    fn ensure_unserved(&self) {
+        if let Some(pid) = self.served_by() {
+            eprintln!(
+                "{} is served by the bridge running as pid {}, stop it first or use its admin api",
+                self.name(),
+                pid
+            );
+            std::process::exit(1);
+        }
+    }
 
-    Ok(match x.event_type.as_str() {
-        "ping" => {
-            let pong = Reply {
-                content: "pong".into(),
+    /// The store, not loaded yet, with the limits of `serve` if given.
+    fn open(&self, serve: Option<&BridgeCmdServe>) -> Box<dyn ApplicationStore> {
+        let url = self.url();
+        let key = self.key();
+        if let Some(path) = url.strip_prefix("sqlite://") {
+            let mut sqlite = match SqliteStore::open(path) {
+                Ok(sqlite) => sqlite,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
             };
-            let mut res = tide::Response::new(StatusCode::Ok);
-            res.body_json(&pong)?;
-            res
+            sqlite.max_integrations = serve.and_then(|cmd| cmd.max_integrations);
+            sqlite.key = key;
+            Box::new(sqlite)
+        } else if let Some(path) = url.strip_prefix("file://") {
+            let mut file = FileStore::new(path);
+            if let Some(cmd) = serve {
+                file.max_integrations = cmd.max_integrations;
+                file.create_if_missing = !cmd.no_auto_create;
+                file.max_size = cmd.max_db_size;
+            }
+            file.key = key;
+            Box::new(file)
+        } else {
+            eprintln!(
+                "unknown store {:?}, expected file://path or sqlite://path",
+                url
+            );
+            std::process::exit(2);
         }
-        "message" => {
-            let mut res = tide::Response::new(200);
-            res.body_json(&json!({"content": ""}))?;
-            res
+    }
+
+    /// Opens and loads the store, exiting when it can't be.
+    fn load(&self) -> Box<dyn ApplicationStore> {
+        let mut store = self.open(None);
+        store.load();
+        store
+    }
+
+    /// Every stored integration as json, its credentials decrypted, for
+    /// checking entries that may not load.
+    fn entries(&self) -> Result<Vec<serde_json::Value>, String> {
+        let url = self.url();
+        let mut entries: Vec<serde_json::Value> = match url.strip_prefix("sqlite://") {
+            Some(path) => {
+                let sqlite = SqliteStore::open(path)?;
+                sqlite.check()?;
+                sqlite
+                    .rows()
+                    .iter()
+                    .map(|row| serde_json::from_str(row))
+                    .collect::<Result<_, _>>()
+                    .map_err(|err| format!("invalid row in {}: {}", path, err))?
+            }
+            None => {
+                let path = url.strip_prefix("file://").unwrap_or(&url);
+                let data = std::fs::read_to_string(path)
+                    .map_err(|err| format!("failed to read {}: {}", path, err))?;
+                serde_json::from_str(&data)
+                    .map_err(|err| format!("{} is corrupt: {}", path, err))?
+            }
+        };
+        if let Some(key) = self.key() {
+            for entry in &mut entries {
+                for_each_sensitive(entry, &mut |stored| key.decrypt(stored))?;
+            }
         }
-        "uninstall" => {
-            state.unregister_twist_thread(x.install_id.unwrap());
-            let mut res = tide::Response::new(200);
-            res.body_json(&json!({"content": "uninstalled!"}))?;
-            res
+        Ok(entries)
+    }
+}
+
+#[derive(Clone)]
+struct State {
+    server_name: String,
+    store: std::sync::Arc<async_std::sync::RwLock<Box<dyn ApplicationStore>>>,
+    opts: std::sync::Arc<BridgeCmdServe>,
+    dedup: std::sync::Arc<std::sync::Mutex<Deduplicator>>,
+    render: std::sync::Arc<RenderOptions>,
+    sender: std::sync::Arc<dyn OutboundSender>,
+    maintenance: std::sync::Arc<std::sync::Mutex<Vec<MaintenanceWindow>>>,
+    in_flight: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<std::net::IpAddr, usize>>>,
+    stats: std::sync::Arc<std::sync::Mutex<DeliveryStats>>,
+    /// when `--health-probe-url` was last probed and whether it answered
+    probe: std::sync::Arc<std::sync::Mutex<Option<(u64, bool)>>>,
+    /// epoch seconds until which alerts are recorded but not posted
+    quiet_until: u64,
+    /// alerts held back during the `--startup-quiet` period
+    quiet_suppressed: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    /// shared http client, cheap to clone
+    http: reqwest::Client,
+    /// set once a shutdown signal arrived, failing /ready
+    draining: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// set once the drain period is over, refusing new webhooks and configures
+    closing: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// webhooks and configures being handled, waited for before exiting
+    busy: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    metrics: std::sync::Arc<Metrics>,
+}
+
+/// Counters served at /metrics, since the process started.
+#[derive(Default)]
+struct Metrics {
+    webhooks_received: std::sync::atomic::AtomicU64,
+    forwards_succeeded: std::sync::atomic::AtomicU64,
+    forwards_failed: std::sync::atomic::AtomicU64,
+    integrations_registered: std::sync::atomic::AtomicU64,
+    integrations_unregistered: std::sync::atomic::AtomicU64,
+    /// twist post durations by outcome and, with `--latency-by-host`, host
+    post_latency: std::sync::Mutex<std::collections::BTreeMap<(String, String), Histogram>>,
+}
+
+/// Upper bounds, in seconds, of the post latency buckets. Twist usually
+/// answers in well under a second, the tail is there for hung posts.
+const LATENCY_BUCKETS: [f64; 10] = [0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// observations per bucket of `LATENCY_BUCKETS`, plus one past the last
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        let idx = LATENCY_BUCKETS
+            .iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[idx] += 1;
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+impl Metrics {
+    fn inc(counter: &std::sync::atomic::AtomicU64) {
+        counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn observe_post(&self, outcome: &str, host: &str, elapsed: std::time::Duration) {
+        self.post_latency
+            .lock()
+            .unwrap()
+            .entry((outcome.to_string(), host.to_string()))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Prometheus text exposition format.
+    fn render(&self) -> String {
+        let counters = [
+            (
+                "bridge_gcp_webhooks_received_total",
+                "GCP webhook requests received",
+                &self.webhooks_received,
+            ),
+            (
+                "bridge_forwards_succeeded_total",
+                "Alerts Twist accepted",
+                &self.forwards_succeeded,
+            ),
+            (
+                "bridge_forwards_failed_total",
+                "Alerts that could not be posted to Twist",
+                &self.forwards_failed,
+            ),
+            (
+                "bridge_integrations_registered_total",
+                "Twist integrations configured",
+                &self.integrations_registered,
+            ),
+            (
+                "bridge_integrations_unregistered_total",
+                "Twist integrations uninstalled",
+                &self.integrations_unregistered,
+            ),
+        ];
+        counters
+            .iter()
+            .map(|(name, help, counter)| {
+                format!(
+                    "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n",
+                    name = name,
+                    help = help,
+                    value = counter.load(std::sync::atomic::Ordering::Relaxed)
+                )
+            })
+            .collect::<String>()
+            + &self.render_latency()
+    }
+
+    fn render_latency(&self) -> String {
+        let name = "bridge_twist_post_duration_seconds";
+        let mut out = format!(
+            "# HELP {name} Duration of posts to Twist, per attempt\n# TYPE {name} histogram\n",
+            name = name
+        );
+        for ((outcome, host), histogram) in self.post_latency.lock().unwrap().iter() {
+            let labels = if host.is_empty() {
+                format!("outcome=\"{}\"", outcome)
+            } else {
+                format!("outcome=\"{}\",host=\"{}\"", outcome, host)
+            };
+            let bounds = LATENCY_BUCKETS
+                .iter()
+                .map(f64::to_string)
+                .chain(std::iter::once(String::from("+Inf")));
+            let mut cumulative = 0;
+            for (le, count) in bounds.zip(histogram.buckets) {
+                cumulative += count;
+                out += &format!(
+                    "{}_bucket{{{},le=\"{}\"}} {}\n",
+                    name, labels, le, cumulative
+                );
+            }
+            out += &format!("{}_sum{{{}}} {}\n", name, labels, histogram.sum);
+            out += &format!("{}_count{{{}}} {}\n", name, labels, histogram.count);
         }
-        _ => tide::Response::new(400),
-    })
+        out
+    }
 }
 
-async fn twist_configure(req: Request<State>) -> tide::Result {
-    let x: TwistOnConfigure = req.query()?;
-    let state = req.state();
+impl State {
+    pub fn new(
+        name: &str,
+        store: Box<dyn ApplicationStore>,
+        opts: BridgeCmdServe,
+        render: RenderOptions,
+        sender: std::sync::Arc<dyn OutboundSender>,
+        http: reqwest::Client,
+    ) -> Self {
+        let dedup = Deduplicator::from_serve(&opts);
+        let stats = DeliveryStats::load(opts.stats_file.clone());
+        Self {
+            server_name: name.to_string(),
+            store: std::sync::Arc::new(async_std::sync::RwLock::new(store)),
+            opts: std::sync::Arc::new(opts),
+            dedup: std::sync::Arc::new(std::sync::Mutex::new(dedup)),
+            render: std::sync::Arc::new(render),
+            sender,
+            maintenance: std::sync::Arc::new(std::sync::Mutex::new(Vec::new())),
+            in_flight: Default::default(),
+            stats: std::sync::Arc::new(std::sync::Mutex::new(stats)),
+            probe: Default::default(),
+            quiet_until: unix_now() + opts.startup_quiet,
+            quiet_suppressed: Default::default(),
+            http,
+            draining: Default::default(),
+            closing: Default::default(),
+            busy: Default::default(),
+            metrics: Default::default(),
+        }
+    }
+}
 
-    let mut k = state.store.lock().unwrap();
-    k.register_twist_thread(x.clone());
+/// How the dedup window is measured.
+///
+/// `sliding` suppresses a repeat that arrives within the window of the last
+/// post for the same incident. `fixed` cuts time into consecutive windows and
+/// lets one post per incident through in each, so two repeats straddling a
+/// window boundary are both posted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DedupWindowKind {
+    Sliding,
+    Fixed,
+}
 
-    tide::log::info!("configure for {} on {}", x.user_name, x.post_data_url);
+impl std::str::FromStr for DedupWindowKind {
+    type Err = String;
 
-    let res = reqwest::blocking::Client::new()
-        .request(reqwest::Method::POST, x.post_data_url)
-        .body(serde_json::to_vec(&json!({
-            "content": "Hello from the other side.",
-        }))?)
-        .header("Content-Type", "application/json")
-        .send()
-        .unwrap();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sliding" => Ok(DedupWindowKind::Sliding),
+            "fixed" => Ok(DedupWindowKind::Fixed),
+            _ => Err(format!(
+                "unknown dedup window {:?}, expected sliding or fixed",
+                s
+            )),
+        }
+    }
+}
 
-    let gcp_url = format!(
-        "https://{}/gcp/webhooks/{}",
-        state.server_name, x.install_id
-    );
+/// Remembers when each incident was last posted so repeats can be dropped.
+struct Deduplicator {
+    window_secs: u64,
+    kind: DedupWindowKind,
+    last_posted: std::collections::HashMap<String, u64>,
+    /// where `last_posted` is persisted across restarts
+    state_file: Option<String>,
+    /// until when, in epoch seconds, `restart_window_secs` applies instead
+    restart_grace_until: u64,
+    restart_window_secs: u64,
+}
 
-    Ok(format!(
-        "
-Twist configuration successful.
+impl Deduplicator {
+    pub fn new(window_secs: u64, kind: DedupWindowKind) -> Self {
+        Self {
+            window_secs,
+            kind,
+            last_posted: std::collections::HashMap::new(),
+            state_file: None,
+            restart_grace_until: 0,
+            restart_window_secs: 0,
+        }
+    }
 
-# GCP Notification Channel
-Webhook URL: {}
+    pub fn from_serve(cmd: &BridgeCmdServe) -> Self {
+        let mut dedup = Self::new(cmd.dedup_window_secs, cmd.dedup_window_kind);
+        dedup.restart_grace_until = unix_now() + cmd.dedup_restart_grace_secs;
+        dedup.restart_window_secs = cmd.dedup_restart_window_secs;
+
+        if let Some(path) = &cmd.dedup_state_file {
+            match std::fs::read_to_string(path) {
+                Ok(data) => match serde_json::from_str(&data) {
+                    Ok(last_posted) => dedup.last_posted = last_posted,
+                    Err(err) => tide::log::warn!("ignoring dedup state in {}: {}", path, err),
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => tide::log::warn!("failed to read dedup state {}: {}", path, err),
+            }
+            dedup.state_file = Some(path.clone());
+        }
+        dedup
+    }
 
-A hello message has been sent to your thread and will appear within 2 hours.
+    /// The window in force at `now`. Right after a restart the persisted
+    /// state may be slightly stale, so the wider restart window applies.
+    fn window_at(&self, now: u64) -> u64 {
+        if now < self.restart_grace_until {
+            self.window_secs.max(self.restart_window_secs)
+        } else {
+            self.window_secs
+        }
+    }
 
-GCP Notifications will be relayed in hourly batches.
-",
-        gcp_url
-    )
-    .into())
+    fn suppresses(&self, last: u64, now: u64) -> bool {
+        let window = self.window_at(now);
+        match self.kind {
+            _ if window == 0 => false,
+            DedupWindowKind::Sliding => now.saturating_sub(last) < window,
+            DedupWindowKind::Fixed => now / window == last / window,
+        }
+    }
+
+    /// Returns whether an alert for `key` should be posted at `now`, recording
+    /// the post if so.
+    fn admit(&mut self, key: &str, now: u64) -> bool {
+        if self.window_at(now) == 0 {
+            return true;
+        }
+
+        let mut last_posted = std::mem::take(&mut self.last_posted);
+        last_posted.retain(|_, last| self.suppresses(*last, now));
+        self.last_posted = last_posted;
+
+        if self.last_posted.contains_key(key) {
+            false
+        } else {
+            self.last_posted.insert(key.to_string(), now);
+            self.persist();
+            true
+        }
+    }
+
+    /// Undoes the `admit` of `key`, for an alert twist then did not take, so
+    /// that its redelivery is posted rather than dropped as a repeat.
+    fn forget(&mut self, key: &str) {
+        if self.last_posted.remove(key).is_some() {
+            self.persist();
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(path) = &self.state_file {
+            let result = serde_json::to_string(&self.last_posted)
+                .map_err(std::io::Error::from)
+                .and_then(|data| write_atomically(path, &data));
+            if let Err(err) = result {
+                tide::log::warn!("failed to persist dedup state to {}: {}", path, err);
+            }
+        }
+    }
+}
+
+/// How alerts fared for one integration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct IntegrationStats {
+    succeeded: u64,
+    failed: u64,
+    /// epoch seconds of the last successful post
+    last_success: Option<u64>,
+}
+
+/// Delivery counters of every integration, keyed by install id.
+#[derive(Default)]
+struct DeliveryStats {
+    by_install: std::collections::HashMap<String, IntegrationStats>,
+    /// where the counters are persisted, kept in memory only if unset
+    state_file: Option<String>,
+    /// whether there are counts `state_file` does not have yet
+    dirty: bool,
+}
+
+impl DeliveryStats {
+    fn load(state_file: Option<String>) -> Self {
+        let mut stats = Self::default();
+        if let Some(path) = &state_file {
+            match std::fs::read_to_string(path) {
+                Ok(data) => match serde_json::from_str(&data) {
+                    Ok(by_install) => stats.by_install = by_install,
+                    Err(err) => tide::log::warn!("ignoring delivery stats in {}: {}", path, err),
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => tide::log::warn!("failed to read delivery stats {}: {}", path, err),
+            }
+        }
+        stats.state_file = state_file;
+        stats
+    }
+
+    fn record(&mut self, install_id: &str, success: bool, now: u64) {
+        let stats = self.by_install.entry(install_id.to_string()).or_default();
+        if success {
+            stats.succeeded += 1;
+            stats.last_success = Some(now);
+        } else {
+            stats.failed += 1;
+        }
+        self.dirty = true;
+    }
+
+    fn get(&self, install_id: &str) -> IntegrationStats {
+        self.by_install.get(install_id).cloned().unwrap_or_default()
+    }
+
+    fn persist(&mut self) {
+        let path = match &self.state_file {
+            Some(path) if self.dirty => path,
+            _ => return,
+        };
+        let result = serde_json::to_string(&self.by_install)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomically(path, &data));
+        match result {
+            Ok(()) => self.dirty = false,
+            Err(err) => tide::log::warn!("failed to persist delivery stats to {}: {}", path, err),
+        }
+    }
+}
+
+/// Writes the delivery stats to `--stats-file` every `--stats-interval`.
+fn persist_stats(state: State) {
+    let interval = std::time::Duration::from_secs(state.opts.stats_interval.max(1));
+    loop {
+        std::thread::sleep(interval);
+        state.stats.lock().unwrap().persist();
+    }
+}
+
+/// Renders epoch seconds as an ISO 8601 UTC time, e.g. `2024-05-01T13:37:00Z`.
+fn format_utc(epoch: u64) -> String {
+    i64::try_from(epoch)
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map_or(epoch.to_string(), |at| {
+            at.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+        })
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TwistOnConfigure {
+    install_id: String,
+    post_data_url: String,
+    user_id: String,
+    user_name: String,
+    /// kept on the integration instead, see `TwistIntegration::verify_token`
+    #[serde(default, skip_serializing)]
+    verify_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum GoogleWebhookPayload {
+    GoogleLogAlert(GoogleLogAlert),
+    GoogleUptimeAlert(GoogleUptimeAlert),
+    GoogleBudgetAlert(GoogleBudgetAlert),
+}
+
+/// A billing budget notification. It has no `incident`, so it can't be
+/// mistaken for the other variants, nor they for it.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleBudgetAlert {
+    budget_display_name: String,
+    cost_amount: f64,
+    budget_amount: f64,
+    currency_code: String,
+    /// fraction of the budget crossed, e.g. `0.9`, absent until the first
+    /// threshold is
+    #[serde(default)]
+    alert_threshold_exceeded: Option<f64>,
+    #[serde(default)]
+    forecast_threshold_exceeded: Option<f64>,
+    #[serde(default)]
+    cost_interval_start: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GoogleUptimeAlert {
+    incident: GoogleUptimeIncident,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GoogleUptimeIncident {
+    policy_name: String,
+    url: String,
+    summary: String,
+    state: String,
+    #[serde(default)]
+    severity: Option<String>,
+    #[serde(default)]
+    started_at: Option<u64>,
+    #[serde(default)]
+    ended_at: Option<u64>,
+    #[serde(default, alias = "image_url")]
+    chart_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GoogleLogAlert {
+    incident: GoogleLogIncident,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GoogleLogIncident {
+    documentation: AlertDocumentation,
+    policy_name: String,
+    #[serde(default)]
+    resource: Option<GoogleResource>,
+    /// set instead of `resource` by incidents spanning several resources
+    #[serde(default)]
+    resources: Vec<GoogleResource>,
+    url: String,
+    #[serde(default)]
+    severity: Option<String>,
+    #[serde(default)]
+    started_at: Option<u64>,
+    #[serde(default)]
+    ended_at: Option<u64>,
+    #[serde(default, alias = "image_url")]
+    chart_url: Option<String>,
+}
+
+impl GoogleLogIncident {
+    fn affected_resources(&self) -> Vec<&GoogleResource> {
+        self.resource.iter().chain(self.resources.iter()).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GoogleResource {
+    labels: serde_json::Value,
+    #[serde(rename = "type")]
+    resource_type: String,
+}
+
+impl GoogleResource {
+    fn label(&self, name: &str) -> Option<&str> {
+        self.labels
+            .as_object()
+            .and_then(|labels| labels.get(name))
+            .and_then(|value| value.as_str())
+    }
+
+    /// A one line summary such as `k8s_container api`.
+    fn describe(&self) -> String {
+        [
+            "container_name",
+            "pod_name",
+            "instance_id",
+            "service_name",
+            "function_name",
+            "cluster_name",
+        ]
+        .iter()
+        .find_map(|name| self.label(name))
+        .map_or(self.resource_type.clone(), |name| {
+            format!("{} {}", self.resource_type, name)
+        })
+    }
+
+    /// All labels as e.g. `namespace_name=prod, project_id=example`, sorted by
+    /// name.
+    fn label_list(&self) -> String {
+        let mut labels: Vec<(&String, &str)> = self
+            .labels
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, value)| Some((name, value.as_str()?)))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        labels.sort();
+        labels
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AlertDocumentation {
+    content: String,
+    mime_type: String,
+}
+
+impl AlertDocumentation {
+    /// The documentation as markdown, escaping plain text so characters like
+    /// `*` and `_` show up literally instead of being read as formatting.
+    fn as_markdown(&self) -> String {
+        if self.mime_type != "text/plain" {
+            return self.content.clone();
+        }
+        let mut escaped = String::with_capacity(self.content.len());
+        for c in self.content.chars() {
+            if matches!(
+                c,
+                '\\' | '`' | '*' | '_' | '[' | ']' | '#' | '>' | '~' | '|'
+            ) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+}
+
+#[derive(FromArgs)]
+/// Relay GCP monitoring notifications into Twist threads.
+struct Bridge {
+    #[argh(subcommand)]
+    cmd: BridgeSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum BridgeSubcommand {
+    Serve(BridgeCmdServe),
+    #[cfg(feature = "dev-tools")]
+    BenchRender(BridgeCmdBenchRender),
+    List(BridgeCmdList),
+    CheckDb(BridgeCmdCheckDb),
+    PrintReply(BridgeCmdPrintReply),
+    SimulateUninstall(BridgeCmdSimulateUninstall),
+    Golden(BridgeCmdGolden),
+    FmtDb(BridgeCmdFmtDb),
+    Replay(BridgeCmdReplay),
+    Remove(BridgeCmdRemove),
+    Mute(BridgeCmdMute),
+    Unmute(BridgeCmdUnmute),
+    Receipts(BridgeCmdReceipts),
+    TestSend(BridgeCmdTestSend),
+}
+
+#[derive(FromArgs, Clone)]
+/// Run the bridge web server.
+#[argh(subcommand, name = "serve")]
+struct BridgeCmdServe {
+    /// public host[:port] used when building webhook urls
+    #[argh(option, default = "String::from(\"tuta.smeten.se\")")]
+    server_name: String,
+
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// base64 encoded 32 byte key encrypting the credentials in --db, which
+    /// are kept in plaintext without one
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key, keeping it out of the process list
+    #[argh(option)]
+    db_key_file: Option<String>,
+
+    /// most thread urls, severity routes and the configured thread together,
+    /// a single integration may post to
+    #[argh(option, default = "10")]
+    max_targets_per_integration: usize,
+
+    /// largest --db, in bytes, loaded at startup, unlimited by default
+    #[argh(option)]
+    max_db_size: Option<u64>,
+
+    /// exit instead of starting with an empty store when --db is missing
+    #[argh(switch)]
+    no_auto_create: bool,
+
+    /// address to listen on, or unix:/path/to.sock for a unix socket
+    #[argh(option, default = "String::from(\"0.0.0.0:9999\")")]
+    bind_addr: String,
+
+    /// bearer token guarding the /admin endpoints, which are disabled without it
+    #[argh(option)]
+    admin_token: Option<String>,
+
+    /// drop repeats of an incident posted within this many seconds, 0 disables
+    #[argh(option, default = "0")]
+    dedup_window_secs: u64,
+
+    /// how the dedup window is measured: sliding (since the last post) or
+    /// fixed (consecutive windows)
+    #[argh(option, default = "DedupWindowKind::Sliding")]
+    dedup_window_kind: DedupWindowKind,
+
+    /// file the dedup state is kept in so it survives restarts
+    #[argh(option)]
+    dedup_state_file: Option<String>,
+
+    /// seconds after startup during which --dedup-restart-window-secs applies
+    #[argh(option, default = "0")]
+    dedup_restart_grace_secs: u64,
+
+    /// wider dedup window used during the restart grace, to absorb repeats the
+    /// persisted state may have missed
+    #[argh(option, default = "0")]
+    dedup_restart_window_secs: u64,
+
+    /// what to do when a configure shares its post_data_url with an existing
+    /// integration: keep (both) or replace (the older one)
+    #[argh(option, default = "ConfigureDedup::Keep")]
+    configure_dedup: ConfigureDedup,
+
+    /// seconds a post to twist may take, connecting included, before it is
+    /// abandoned and retried
+    #[argh(option, default = "10")]
+    request_timeout_secs: u64,
+
+    /// label the post latency histogram at /metrics by twist host
+    #[argh(switch)]
+    latency_by_host: bool,
+
+    /// message posted to a thread when an integration is configured
+    #[argh(option, default = "String::from(\"Hello from the other side.\")")]
+    install_message: String,
+
+    /// configure integrations without posting the --install-message
+    #[argh(switch)]
+    no_install_message: bool,
+
+    /// refuse, rather than only warn about, configures sharing their
+    /// post_data_url with an existing integration under --configure-dedup keep
+    #[argh(switch)]
+    reject_duplicate_urls: bool,
+
+    /// Pub/Sub message attribute naming the integration to post to, falling
+    /// back to the id in the webhook url when absent
+    #[argh(option)]
+    pubsub_route_attribute: Option<String>,
+
+    /// refuse new configures once this many integrations are registered
+    #[argh(option)]
+    max_integrations: Option<usize>,
+
+    /// file with the message template for resolved incidents, e.g.
+    /// "✅ {policy_name} resolved after {duration}"
+    #[argh(option)]
+    resolved_template_file: Option<String>,
+
+    /// file with the message template for every alert, replacing the built-in
+    /// layouts, e.g. "{state_emoji} {policy_name} @team-sre {url}", see
+    /// `template_fields` for the placeholders of each alert type
+    #[argh(option)]
+    template_file: Option<String>,
+
+    /// file with a footer appended to every alert, e.g.
+    /// "[incident]({url}) · [runbook]({runbook_url}) · reply `ack` to acknowledge",
+    /// see `render_footer`, integrations can override it
+    #[argh(option)]
+    footer_template_file: Option<String>,
+
+    /// append a json line per forward attempt to this file, rotation is left
+    /// to the operator
+    #[argh(option)]
+    receipts_log: Option<String>,
+
+    /// how many times a failed post to twist is retried
+    #[argh(option, default = "0")]
+    max_retries: u32,
+
+    /// delay before the first retry in milliseconds, doubling on each retry
+    #[argh(option, default = "500")]
+    retry_base_ms: u64,
+
+    /// give up retrying a post after this many seconds, whatever the attempt
+    /// count
+    #[argh(option)]
+    max_retry_duration: Option<u64>,
+
+    /// run as a standby, periodically copying integrations from this primary
+    /// base url, authenticating with --admin-token
+    #[argh(option)]
+    replicate_from: Option<String>,
+
+    /// seconds between pulls from the --replicate-from primary
+    #[argh(option, default = "60")]
+    replicate_interval: u64,
+
+    /// longest message, in characters, posted to twist
+    #[argh(option, default = "DEFAULT_CONTENT_LIMIT")]
+    content_limit: usize,
+
+    /// longest log alert documentation, in characters, posted before it is
+    /// cut off with a link to the incident
+    #[argh(option, default = "DEFAULT_DOCS_LIMIT")]
+    docs_limit: usize,
+
+    /// echo unparseable payloads as received instead of pretty-printing them
+    #[argh(switch)]
+    compact_parse_failures: bool,
+
+    /// regex whose matches are replaced with [redacted] in every posted
+    /// message, may be repeated
+    #[argh(option)]
+    redact: Vec<String>,
+
+    /// https thread url told about every (re)start of the bridge
+    #[argh(option)]
+    startup_notify_url: Option<String>,
+
+    /// log, at debug level, gcp payload fields the bridge does not model
+    #[argh(switch)]
+    warn_unknown_fields: bool,
+
+    /// merge the alerts of a batched delivery that share a policy, and the
+    /// thread they are routed to, into one message
+    #[argh(switch)]
+    group_batches: bool,
+
+    /// how batched deliveries are posted: separate (a message per alert, or
+    /// per policy with --group-batches) or combined (one message)
+    #[argh(option, default = "BatchMode::Separate")]
+    batch_mode: BatchMode,
+
+    /// append posts twist did not take, after all retries, to this file for
+    /// `replay --deadletter-file`
+    #[argh(option)]
+    deadletter_file: Option<String>,
+
+    /// log format: text, or json with the context of each line as fields
+    #[argh(option, default = "LogFormat::Text")]
+    log_format: LogFormat,
+
+    /// twist url /healthz checks outbound connectivity against, which is not
+    /// checked without it
+    #[argh(option)]
+    health_probe_url: Option<String>,
+
+    /// seconds a --health-probe-url result is reused for
+    #[argh(option, default = "60")]
+    health_probe_interval: u64,
+
+    /// seconds after startup during which alerts are recorded for dedup but
+    /// not posted, absorbing redeliveries of alerts sent before a restart
+    #[argh(option, default = "0")]
+    startup_quiet: u64,
+
+    /// name of this bridge, signing every message it posts, for telling
+    /// instances posting to the same threads apart
+    #[argh(option, default = "String::new()")]
+    instance_label: String,
+
+    /// shape of the json bodies exchanged with twist, only v1 so far
+    #[argh(option, default = "TwistBodyVersion::V1")]
+    twist_body_version: TwistBodyVersion,
+
+    /// seconds between a shutdown signal and exiting, during which /ready
+    /// fails but requests are still served
+    #[argh(option, default = "10")]
+    drain_secs: u64,
+
+    /// seconds after --drain-secs to wait for webhooks and configures, and
+    /// their posts to twist, still being handled, new ones being refused
+    #[argh(option, default = "30")]
+    shutdown_grace_secs: u64,
+
+    /// log posts to twist instead of sending them
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// milliseconds a webhook waits for the store before answering 503,
+    /// unbounded by default
+    #[argh(option)]
+    store_lock_timeout: Option<u64>,
+
+    /// reply posted when someone messages the integration, empty for none
+    #[argh(option, default = "String::new()")]
+    message_reply: String,
+
+    /// host metric charts may be embedded from, may be repeated, defaults to
+    /// google's storage and monitoring hosts
+    #[argh(option)]
+    chart_host: Vec<String>,
+
+    /// most requests handled at once for a single source ip, answering 429
+    /// past it, unlimited by default
+    #[argh(option)]
+    per_ip_limit: Option<usize>,
+
+    /// file delivery counters are kept in across restarts, in memory only if
+    /// unset
+    #[argh(option)]
+    stats_file: Option<String>,
+
+    /// seconds between writes of the --stats-file
+    #[argh(option, default = "60")]
+    stats_interval: u64,
+
+    /// internal network, as a cidr, thread urls may nevertheless point into,
+    /// may be repeated, for testing against local services
+    #[argh(option)]
+    allow_target_cidrs: Vec<Cidr>,
+
+    /// network, as a cidr, the gcp webhooks accept requests from, may be
+    /// repeated, any source is accepted without it
+    #[argh(option)]
+    gcp_allowed_cidrs: Vec<Cidr>,
+
+    /// content type accepted on the gcp webhooks, may be repeated, defaults
+    /// to application/json
+    #[argh(option)]
+    gcp_content_type: Vec<String>,
+
+    /// content type accepted on twist's outgoing webhook, may be repeated,
+    /// defaults to application/json
+    #[argh(option)]
+    twist_content_type: Vec<String>,
+
+    /// follow redirects answered to posts instead of failing them
+    #[argh(switch)]
+    follow_redirects: bool,
+
+    /// proxy network, as a cidr, whose X-Forwarded-For header is believed,
+    /// may be repeated
+    #[argh(option)]
+    trusted_proxies: Vec<Cidr>,
+}
+
+/// An ip network such as `10.0.0.0/8`, a bare address being a single host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cidr {
+    addr: std::net::IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn contains(&self, ip: std::net::IpAddr) -> bool {
+        use std::net::IpAddr::{V4, V6};
+        match (self.addr, ip) {
+            (V4(net), V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (V6(net), V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid cidr {:?}, expected address[/prefix]", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: std::net::IpAddr = addr.parse().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// Policy for a configure whose thread is already served by another install.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConfigureDedup {
+    Keep,
+    Replace,
+}
+
+impl std::str::FromStr for ConfigureDedup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(ConfigureDedup::Keep),
+            "replace" => Ok(ConfigureDedup::Replace),
+            _ => Err(format!(
+                "unknown configure dedup policy {:?}, expected keep or replace",
+                s
+            )),
+        }
+    }
+}
+
+/// How `serve` writes its logs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+/// Writes a json object per log line, with the key/values of the log call
+/// (e.g. `install_id`, `incident_url`, `event`) as fields, for Cloud Logging.
+struct JsonLogger;
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", JsonLogger::line(record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl JsonLogger {
+    /// The json object logged for `record`, its key/values as fields.
+    fn line(record: &log::Record) -> String {
+        struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+        impl<'kvs> log::kv::Visitor<'kvs> for Fields<'_> {
+            fn visit_pair(
+                &mut self,
+                key: log::kv::Key<'kvs>,
+                value: log::kv::Value<'kvs>,
+            ) -> Result<(), log::kv::Error> {
+                self.0.insert(key.to_string(), json!(value.to_string()));
+                Ok(())
+            }
+        }
+
+        let mut fields = serde_json::Map::new();
+        fields.insert("time".into(), json!(unix_now()));
+        fields.insert("severity".into(), json!(record.level().to_string()));
+        fields.insert("target".into(), json!(record.target()));
+        fields.insert("message".into(), json!(record.args().to_string()));
+        let _ = record.key_values().visit(&mut Fields(&mut fields));
+        serde_json::Value::Object(fields).to_string()
+    }
+}
+
+/// Whether the alerts of a batched delivery are posted one by one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BatchMode {
+    Separate,
+    Combined,
+}
+
+impl std::str::FromStr for BatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "separate" => Ok(BatchMode::Separate),
+            "combined" => Ok(BatchMode::Combined),
+            _ => Err(format!(
+                "unknown batch mode {:?}, expected separate or combined",
+                s
+            )),
+        }
+    }
+}
+
+// only built with `--features dev-tools`, it is of no use to operators
+#[cfg(feature = "dev-tools")]
+#[derive(FromArgs)]
+/// Developer tool: time reply_to_json over a built-in sample payload.
+#[argh(subcommand, name = "bench-render")]
+struct BridgeCmdBenchRender {
+    /// number of renders to time
+    #[argh(option, default = "10000")]
+    iterations: usize,
+
+    /// sample payload to render: uptime, log, budget or invalid
+    #[argh(option, default = "String::from(\"uptime\")")]
+    variant: String,
+}
+
+#[derive(FromArgs)]
+/// Print the registered integrations.
+#[argh(subcommand, name = "list")]
+struct BridgeCmdList {
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// key the store's credentials are encrypted with, as given to serve
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key
+    #[argh(option)]
+    db_key_file: Option<String>,
+
+    /// output format: table, csv, tsv or json
+    #[argh(option, default = "ListFormat::Table")]
+    format: ListFormat,
+
+    /// delivery stats written by serve's --stats-file
+    #[argh(option)]
+    stats_file: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// Print the twist message rendered for gcp payload files.
+#[argh(subcommand, name = "print-reply")]
+struct BridgeCmdPrintReply {
+    /// gcp payload file to render, may be repeated, stdin is read without one
+    #[argh(option)]
+    input_filename: Vec<String>,
+
+    /// print one json object per input, for streaming into other tools
+    #[argh(switch)]
+    jsonl: bool,
+}
+
+#[derive(FromArgs)]
+/// Developer tool: run twist's uninstall event for an integration.
+#[argh(subcommand, name = "simulate-uninstall")]
+struct BridgeCmdSimulateUninstall {
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// key the store's credentials are encrypted with, as given to serve
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key
+    #[argh(option)]
+    db_key_file: Option<String>,
+
+    /// install id twist would send in the uninstall event
+    #[argh(option)]
+    install_id: String,
+}
+
+#[derive(FromArgs)]
+/// Developer tool: compare rendered payloads against their expected output.
+///
+/// Every `name.json` in the directory is rendered and compared with
+/// `name.golden` next to it.
+#[argh(subcommand, name = "golden")]
+struct BridgeCmdGolden {
+    /// directory holding the payloads and goldens
+    #[argh(positional)]
+    dir: String,
+
+    /// rewrite the goldens with the current output instead of comparing
+    #[argh(switch)]
+    update: bool,
+}
+
+#[derive(FromArgs)]
+/// Post the twist message for a gcp payload file to an integration, rendered
+/// the built-in way as a v1 body whatever templates or --twist-body-version
+/// serve is given.
+#[argh(subcommand, name = "replay")]
+struct BridgeCmdReplay {
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// key the store's credentials are encrypted with, as given to serve
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key
+    #[argh(option)]
+    db_key_file: Option<String>,
+
+    /// integration to post to
+    #[argh(option)]
+    install_id: Option<String>,
+
+    /// gcp payload file to render
+    #[argh(option)]
+    input_filename: Option<String>,
+
+    /// retry the posts of this --deadletter-file instead, dropping the ones
+    /// that go through from it
+    #[argh(option)]
+    deadletter_file: Option<String>,
+
+    /// print the post instead of sending it
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// internal network, as a cidr, thread urls may nevertheless point into,
+    /// may be repeated, as given to serve
+    #[argh(option)]
+    allow_target_cidrs: Vec<Cidr>,
+
+    /// follow redirects answered to posts instead of failing them
+    #[argh(switch)]
+    follow_redirects: bool,
+
+    /// seconds a post to twist may take, connecting included
+    #[argh(option, default = "10")]
+    request_timeout_secs: u64,
+}
+
+#[derive(FromArgs)]
+/// Remove an integration from the store.
+#[argh(subcommand, name = "remove")]
+struct BridgeCmdRemove {
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// key the store's credentials are encrypted with, as given to serve
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key
+    #[argh(option)]
+    db_key_file: Option<String>,
+
+    /// integration to remove
+    #[argh(option)]
+    install_id: String,
+}
+
+#[derive(FromArgs)]
+/// Stop posting alerts to an integration's thread until it is unmuted.
+#[argh(subcommand, name = "mute")]
+struct BridgeCmdMute {
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// key the store's credentials are encrypted with, as given to serve
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key
+    #[argh(option)]
+    db_key_file: Option<String>,
+
+    /// integration to mute
+    #[argh(option)]
+    install_id: String,
+}
+
+#[derive(FromArgs)]
+/// Post a test message to an integration's thread, to check it is reachable.
+#[argh(subcommand, name = "test-send")]
+struct BridgeCmdTestSend {
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// key the store's credentials are encrypted with, as given to serve
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key
+    #[argh(option)]
+    db_key_file: Option<String>,
+
+    /// integration to post to
+    #[argh(option)]
+    install_id: String,
+
+    /// internal network, as a cidr, thread urls may nevertheless point into,
+    /// may be repeated, as given to serve
+    #[argh(option)]
+    allow_target_cidrs: Vec<Cidr>,
+
+    /// follow redirects answered to posts instead of failing them
+    #[argh(switch)]
+    follow_redirects: bool,
+
+    /// seconds a post to twist may take, connecting included
+    #[argh(option, default = "10")]
+    request_timeout_secs: u64,
+}
+
+#[derive(FromArgs)]
+/// Print the --receipts-log as a table.
+#[argh(subcommand, name = "receipts")]
+struct BridgeCmdReceipts {
+    /// the --receipts-log the bridge was served with
+    #[argh(option)]
+    log: String,
+
+    /// only show deliveries for this integration
+    #[argh(option)]
+    install_id: Option<String>,
+
+    /// only show deliveries from the last this many seconds
+    #[argh(option)]
+    since: Option<u64>,
+
+    /// only show failed deliveries
+    #[argh(switch)]
+    failures_only: bool,
+}
+
+#[derive(FromArgs)]
+/// Resume posting alerts to a muted integration's thread.
+#[argh(subcommand, name = "unmute")]
+struct BridgeCmdUnmute {
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// key the store's credentials are encrypted with, as given to serve
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key
+    #[argh(option)]
+    db_key_file: Option<String>,
+
+    /// integration to unmute
+    #[argh(option)]
+    install_id: String,
+}
+
+#[derive(FromArgs)]
+/// Lint the integration store and rewrite it sorted and pretty-printed.
+#[argh(subcommand, name = "fmt-db")]
+struct BridgeCmdFmtDb {
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// key the store's credentials are encrypted with, as given to serve
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key
+    #[argh(option)]
+    db_key_file: Option<String>,
+
+    /// fail instead of rewriting when the store is not formatted
+    #[argh(switch)]
+    check: bool,
+}
+
+#[derive(FromArgs)]
+/// Check that every stored integration loads and is valid for this version.
+#[argh(subcommand, name = "check-db")]
+struct BridgeCmdCheckDb {
+    /// path to the integration store
+    #[argh(option, default = "String::from(\"db.json\")")]
+    db: String,
+
+    /// integration store as file://path or sqlite://path, in place of --db
+    #[argh(option)]
+    store: Option<String>,
+
+    /// key the store's credentials are encrypted with, as given to serve
+    #[argh(option)]
+    db_key: Option<String>,
+
+    /// file holding the --db-key
+    #[argh(option)]
+    db_key_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListFormat {
+    Table,
+    Csv,
+    Tsv,
+    Json,
+}
+
+impl std::str::FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(ListFormat::Table),
+            "csv" => Ok(ListFormat::Csv),
+            "tsv" => Ok(ListFormat::Tsv),
+            "json" => Ok(ListFormat::Json),
+            _ => Err(format!(
+                "unknown list format {:?}, expected table, csv, tsv or json",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "dev-tools"))]
+const SAMPLE_UPTIME_ALERT: &str = r#"{"incident": {"policy_name": "api uptime", "url": "https://console.cloud.google.com/monitoring/alerting/incidents/0.abc", "summary": "An uptime check on api.example.com is failing.", "state": "open"}}"#;
+
+#[cfg(any(test, feature = "dev-tools"))]
+const SAMPLE_LOG_ALERT: &str = r#"{"incident": {"documentation": {"content": "Error rate above threshold, see the runbook.", "mime_type": "text/markdown"}, "policy_name": "api errors", "resource": {"labels": {"container_name": "api", "project_id": "example"}, "type": "k8s_container"}, "url": "https://console.cloud.google.com/monitoring/alerting/incidents/0.def"}}"#;
+
+#[cfg(any(test, feature = "dev-tools"))]
+const SAMPLE_BUDGET_ALERT: &str = r#"{"budgetDisplayName": "example monthly", "alertThresholdExceeded": 0.9, "costAmount": 912.4, "costIntervalStart": "2024-05-01T07:00:00Z", "budgetAmount": 1000.0, "budgetAmountType": "SPECIFIED_AMOUNT", "currencyCode": "USD"}"#;
+
+#[async_std::main]
+async fn main() -> tide::Result<()> {
+    let bridge: Bridge = argh::from_env();
+    match bridge.cmd {
+        BridgeSubcommand::Serve(cmd) => serve(cmd).await,
+        #[cfg(feature = "dev-tools")]
+        BridgeSubcommand::BenchRender(cmd) => bench_render(cmd),
+        BridgeSubcommand::List(cmd) => list(cmd),
+        BridgeSubcommand::CheckDb(cmd) => check_db(cmd),
+        BridgeSubcommand::PrintReply(cmd) => print_reply(cmd),
+        BridgeSubcommand::SimulateUninstall(cmd) => simulate_uninstall(cmd),
+        BridgeSubcommand::Golden(cmd) => golden(cmd),
+        BridgeSubcommand::FmtDb(cmd) => fmt_db(cmd),
+        BridgeSubcommand::Replay(cmd) => replay(cmd).await,
+        BridgeSubcommand::Remove(cmd) => remove(cmd),
+        BridgeSubcommand::Mute(cmd) => set_muted(
+            StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file),
+            &cmd.install_id,
+            true,
+        ),
+        BridgeSubcommand::Unmute(cmd) => set_muted(
+            StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file),
+            &cmd.install_id,
+            false,
+        ),
+        BridgeSubcommand::Receipts(cmd) => receipts(cmd),
+        BridgeSubcommand::TestSend(cmd) => test_send(cmd).await,
+    }
+}
+
+fn receipts(cmd: BridgeCmdReceipts) -> tide::Result<()> {
+    let data = std::fs::read_to_string(&cmd.log)?;
+    let since = cmd.since.map(|secs| unix_now().saturating_sub(secs));
+    let mut rows = Vec::new();
+    for (idx, line) in data
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+    {
+        let receipt: Receipt = match serde_json::from_str(line) {
+            Ok(receipt) => receipt,
+            Err(err) => {
+                eprintln!("skipping line {} of {}: {}", idx + 1, cmd.log, err);
+                continue;
+            }
+        };
+        let id = receipt.install_id.as_deref().unwrap_or(&receipt.webhook_id);
+        if cmd.install_id.as_deref().is_some_and(|wanted| wanted != id)
+            || since.is_some_and(|since| receipt.timestamp < since)
+            || (cmd.failures_only && receipt.outcome == "success")
+        {
+            continue;
+        }
+        let outcome = match (&receipt.status, &receipt.error) {
+            (_, Some(err)) => format!("{}: {}", receipt.outcome, err),
+            (Some(status), None) => format!("{} ({})", receipt.outcome, status),
+            (None, None) => receipt.outcome.clone(),
+        };
+        rows.push([
+            format_utc(receipt.timestamp),
+            id.to_string(),
+            receipt.incident_url.clone().unwrap_or_default(),
+            outcome,
+        ]);
+    }
+
+    let header = ["time", "id", "incident", "outcome"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let print_row = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    print_row(header.to_vec());
+    for row in &rows {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+    Ok(())
+}
+
+/// Saves the mute flag of an integration. A running bridge would overwrite a
+/// json store with what it read at startup, so it has to be stopped first, or
+/// muted through `/admin/integrations/:id/mute` instead.
+fn set_muted(args: StoreArgs, install_id: &str, muted: bool) -> tide::Result<()> {
+    args.ensure_unserved();
+    let mut store = args.load();
+    if !store.update_twist_thread(install_id, &mut |twist| twist.muted = muted) {
+        eprintln!("no integration {} in {}", install_id, args.name());
+        std::process::exit(1);
+    }
+    println!(
+        "{} {} in {}",
+        if muted { "muted" } else { "unmuted" },
+        install_id,
+        args.name()
+    );
+    Ok(())
+}
+
+fn remove(cmd: BridgeCmdRemove) -> tide::Result<()> {
+    let args = StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file);
+    args.ensure_unserved();
+    let mut store = args.load();
+    if !store.unregister_twist_thread(cmd.install_id.clone()) {
+        eprintln!("no integration {} in {}", cmd.install_id, args.name());
+        std::process::exit(1);
+    }
+    println!("removed {} from {}", cmd.install_id, args.name());
+    Ok(())
+}
+
+async fn replay(cmd: BridgeCmdReplay) -> tide::Result<()> {
+    let args = StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file);
+    let store = args.load();
+    let sender = ReqwestSender::new(
+        cmd.follow_redirects,
+        &cmd.allow_target_cidrs,
+        cmd.request_timeout_secs,
+    )?;
+    if let Some(path) = &cmd.deadletter_file {
+        let (delivered, remaining) =
+            replay_dead_letters(&*store, &sender, path, cmd.dry_run).await?;
+        println!("delivered {}, {} left in {}", delivered, remaining, path);
+        if delivered == 0 && remaining > 0 && !cmd.dry_run {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let (install_id, input_filename) = match (&cmd.install_id, &cmd.input_filename) {
+        (Some(install_id), Some(input_filename)) => (install_id, input_filename),
+        _ => {
+            eprintln!("replay needs --install-id and --input-filename, or --deadletter-file");
+            std::process::exit(2);
+        }
+    };
+    let twist = match store.find_twist_thread(install_id.clone()) {
+        Some(twist) => twist,
+        None => {
+            eprintln!("no integration {} in {}", install_id, args.name());
+            std::process::exit(1);
+        }
+    };
+
+    let reply = reply_to_json(
+        std::fs::read_to_string(input_filename)?,
+        &RenderOptions::default(),
+    );
+    let body = TwistBodyVersion::V1.body(reply.content, false);
+    let url = twist.post_url_for(
+        reply.payload.as_ref().and_then(|p| p.severity()),
+        reply.payload.as_ref().map(|p| p.policy_name()),
+    );
+    if cmd.dry_run {
+        println!("POST {}\n{}", redact_url(url), body);
+        return Ok(());
+    }
+
+    match sender.post(url, twist.auth_token.as_ref(), &body).await {
+        Ok(status) if is_success(status) => println!("twist answered {}", status),
+        Ok(status) => {
+            println!("twist answered {}", status);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("failed to post to {}: {}", redact_url(url), err);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+async fn test_send(cmd: BridgeCmdTestSend) -> tide::Result<()> {
+    let args = StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file);
+    let twist = match args.load().find_twist_thread(cmd.install_id.clone()) {
+        Some(twist) => twist,
+        None => {
+            eprintln!("no integration {} in {}", cmd.install_id, args.name());
+            std::process::exit(1);
+        }
+    };
+
+    let sender = ReqwestSender::new(
+        cmd.follow_redirects,
+        &cmd.allow_target_cidrs,
+        cmd.request_timeout_secs,
+    )?;
+    let url = &twist.configuration.post_data_url;
+    let body = TwistBodyVersion::V1.body("test message from bridge".into(), false);
+    match sender.post(url, twist.auth_token.as_ref(), &body).await {
+        Ok(status) if is_success(status) => println!("twist answered {}", status),
+        Ok(status) => {
+            println!("twist answered {}", status);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("failed to post to {}: {}", redact_url(url), err);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// Retries every post of the dead-letter file at `path`, appending those that
+/// failed again back to it, and returns how many were delivered and kept.
+///
+/// A running serve keeps appending to `path`, so the file is first moved
+/// aside and only that snapshot is replayed: letters written meanwhile land
+/// in a fresh file and aren't lost by the rewrite.
+async fn replay_dead_letters(
+    store: &dyn ApplicationStore,
+    sender: &dyn OutboundSender,
+    path: &str,
+    dry_run: bool,
+) -> tide::Result<(usize, usize)> {
+    let snapshot = if dry_run {
+        path.to_string()
+    } else {
+        let snapshot = format!("{}.replaying-{}", path, unix_now());
+        std::fs::rename(path, &snapshot)?;
+        snapshot
+    };
+    let mut remaining = Vec::new();
+    let mut delivered = 0;
+    for line in std::fs::read_to_string(&snapshot)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let letter: DeadLetter = match serde_json::from_str(line) {
+            Ok(letter) => letter,
+            Err(err) => {
+                eprintln!("keeping unreadable entry: {}", err);
+                remaining.push(line.to_string());
+                continue;
+            }
+        };
+        let twist = match store.find_twist_thread(letter.install_id.clone()) {
+            Some(twist) => twist,
+            None => {
+                eprintln!(
+                    "keeping entry for unknown integration {}",
+                    letter.install_id
+                );
+                remaining.push(line.to_string());
+                continue;
+            }
+        };
+        let url = twist.post_url_for(letter.severity.as_deref(), letter.policy_name.as_deref());
+        if dry_run {
+            println!("POST {}\n{}", redact_url(url), letter.body);
+            remaining.push(line.to_string());
+            continue;
+        }
+        match sender
+            .post(url, twist.auth_token.as_ref(), &letter.body)
+            .await
+        {
+            Ok(status) if is_success(status) => delivered += 1,
+            outcome => {
+                eprintln!("still failing for {}: {:?}", letter.install_id, outcome);
+                remaining.push(line.to_string());
+            }
+        }
+    }
+
+    if !dry_run {
+        use std::io::Write;
+
+        let data: String = remaining.iter().map(|line| format!("{}\n", line)).collect();
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(data.as_bytes())?;
+        std::fs::remove_file(&snapshot)?;
+    }
+    Ok((delivered, remaining.len()))
+}
+
+/// Formats a json store as stored, its credentials staying encrypted, and
+/// decrypts them only to validate the entries.
+fn fmt_db(cmd: BridgeCmdFmtDb) -> tide::Result<()> {
+    let args = StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file);
+    let url = args.url();
+    let path = match url.strip_prefix("file://") {
+        Some(path) => path.to_string(),
+        None => {
+            eprintln!("fmt-db only formats json stores, not {}", url);
+            std::process::exit(2);
+        }
+    };
+    args.ensure_unserved();
+    let key = args.key();
+    let data = std::fs::read_to_string(&path)?;
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&data)?;
+
+    let mut problems = Vec::new();
+    let mut twists = Vec::new();
+    let mut unparsed = 0;
+    for (idx, entry) in entries.into_iter().enumerate() {
+        let mut plain = entry.clone();
+        if let Some(key) = &key {
+            if let Err(err) = for_each_sensitive(&mut plain, &mut |stored| key.decrypt(stored)) {
+                problems.push(format!("entry {}: {}", idx, err));
+            }
+        }
+        match serde_json::from_value::<TwistIntegration>(entry) {
+            Ok(twist) => {
+                if let Ok(plain) = serde_json::from_value::<TwistIntegration>(plain) {
+                    for problem in validate_integration(&plain) {
+                        problems.push(format!("entry {}: {}", idx, problem));
+                    }
+                }
+                twists.push(twist);
+            }
+            Err(err) => {
+                problems.push(format!("entry {}: {}", idx, err));
+                unparsed += 1;
+            }
+        }
+    }
+    twists.sort_by(|a, b| a.secret_id.cmp(&b.secret_id));
+    for pair in twists.windows(2) {
+        if pair[0].secret_id == pair[1].secret_id {
+            problems.push(format!("duplicate install_id {}", pair[0].secret_id));
+        }
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    // going through Value sorts the keys of every object
+    let formatted = serde_json::to_string_pretty(&serde_json::to_value(&twists)?)? + "\n";
+    if cmd.check {
+        if formatted != data {
+            println!("{} is not formatted", path);
+        }
+        if formatted != data || !problems.is_empty() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // rewriting would drop the entries that did not load
+    if unparsed == 0 {
+        write_atomically(&path, &formatted)?;
+    }
+    if !problems.is_empty() {
+        println!("{} problems in {}", problems.len(), path);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn golden(cmd: BridgeCmdGolden) -> tide::Result<()> {
+    let render = RenderOptions::default();
+    let mut payloads: Vec<std::path::PathBuf> = std::fs::read_dir(&cmd.dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    payloads.sort();
+
+    let mut mismatches = 0;
+    for path in &payloads {
+        let actual = reply_to_json(std::fs::read_to_string(path)?, &render).content;
+        let golden_path = path.with_extension("golden");
+        if cmd.update {
+            std::fs::write(&golden_path, &actual)?;
+            continue;
+        }
+        let expected = match std::fs::read_to_string(&golden_path) {
+            Ok(expected) => expected,
+            Err(err) => {
+                println!("{}: {}", golden_path.display(), err);
+                mismatches += 1;
+                continue;
+            }
+        };
+        if expected == actual {
+            continue;
+        }
+        mismatches += 1;
+        println!("{} differs from {}", path.display(), golden_path.display());
+        let expected: Vec<&str> = expected.lines().collect();
+        let actual: Vec<&str> = actual.lines().collect();
+        for idx in 0..expected.len().max(actual.len()) {
+            let (want, got) = (expected.get(idx), actual.get(idx));
+            if want != got {
+                if let Some(want) = want {
+                    println!("  {:>4} - {}", idx + 1, want);
+                }
+                if let Some(got) = got {
+                    println!("  {:>4} + {}", idx + 1, got);
+                }
+            }
+        }
+    }
+
+    if cmd.update {
+        println!("updated {} goldens in {}", payloads.len(), cmd.dir);
+    } else if mismatches > 0 {
+        println!("{} of {} goldens differ", mismatches, payloads.len());
+        std::process::exit(1);
+    } else {
+        println!("{} goldens match", payloads.len());
+    }
+    Ok(())
+}
+
+fn simulate_uninstall(cmd: BridgeCmdSimulateUninstall) -> tide::Result<()> {
+    tide::log::start();
+
+    let mut store = StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file).load();
+    if !uninstall(&mut *store, cmd.install_id) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn print_reply(cmd: BridgeCmdPrintReply) -> tide::Result<()> {
+    let render = RenderOptions::default();
+    let inputs = read_inputs(&cmd.input_filename, &mut std::io::stdin())?;
+    for (path, json) in inputs {
+        let reply = reply_to_json(json, &render);
+        if cmd.jsonl {
+            println!(
+                "{}",
+                json!({
+                    "file": path,
+                    "variant": reply.payload.as_ref().map(|p| p.variant_name()),
+                    "ok": reply.payload.is_some(),
+                    "reply": reply.content,
+                })
+            );
+        } else {
+            println!("{}", reply.content);
+        }
+    }
+    Ok(())
+}
+
+/// The payloads of `filenames` by name, or of `stdin`, named `-`, without any.
+fn read_inputs(
+    filenames: &[String],
+    stdin: &mut dyn std::io::Read,
+) -> std::io::Result<Vec<(String, String)>> {
+    if filenames.is_empty() {
+        let mut json = String::new();
+        stdin.read_to_string(&mut json)?;
+        return Ok(vec![(String::from("-"), json)]);
+    }
+    filenames
+        .iter()
+        .map(|path| Ok((path.clone(), std::fs::read_to_string(path)?)))
+        .collect()
+}
+
+fn check_db(cmd: BridgeCmdCheckDb) -> tide::Result<()> {
+    let args = StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file);
+    let entries = match args.entries() {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut failures = 0;
+    for (idx, entry) in entries.into_iter().enumerate() {
+        let problems = match serde_json::from_value::<TwistIntegration>(entry) {
+            Ok(twist) => validate_integration(&twist),
+            Err(err) => vec![err.to_string()],
+        };
+        for problem in &problems {
+            println!("entry {}: {}", idx, problem);
+        }
+        if !problems.is_empty() {
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        println!("{} invalid entries in {}", failures, args.name());
+        std::process::exit(1);
+    }
+    println!("{} is valid", args.name());
+    Ok(())
+}
+
+/// Lists what is wrong with a stored integration, if anything.
+fn validate_integration(twist: &TwistIntegration) -> Vec<String> {
+    let mut problems = Vec::new();
+    if twist.secret_id.is_empty() {
+        problems.push("empty secret_id".to_string());
+    }
+    if twist.secret_id != twist.configuration.install_id {
+        problems.push(format!(
+            "secret_id {} does not match install_id {}",
+            twist.secret_id, twist.configuration.install_id
+        ));
+    }
+
+    let urls = std::iter::once((
+        "post_data_url".to_string(),
+        &twist.configuration.post_data_url,
+    ))
+    .chain(
+        twist
+            .severity_routes
+            .iter()
+            .map(|(severity, url)| (format!("severity route {}", severity), url)),
+    )
+    .chain(twist.policy_routes.iter().map(|route| {
+        (
+            format!("policy route {}", route.policy.0.as_str()),
+            &route.post_data_url,
+        )
+    }));
+    for (name, url) in urls {
+        match reqwest::Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+            Ok(parsed) => problems.push(format!("{} has scheme {}", name, parsed.scheme())),
+            Err(err) => problems.push(format!("{} is not a valid url: {}", name, err)),
+        }
+    }
+    problems
+}
+
+fn list(cmd: BridgeCmdList) -> tide::Result<()> {
+    let store = StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file).load();
+
+    let stats = DeliveryStats::load(cmd.stats_file.clone());
+
+    let header = [
+        "install_id",
+        "user_name",
+        "host",
+        "created_at",
+        "enabled",
+        "succeeded",
+        "failed",
+        "last_success",
+    ];
+    let now = unix_now();
+    let rows: Vec<[String; 8]> = store
+        .list_twist_threads()
+        .into_iter()
+        .map(|twist| {
+            let delivered = stats.get(&twist.configuration.install_id);
+            [
+                twist.secret_id.clone(),
+                twist.configuration.user_name.clone(),
+                reqwest::Url::parse(&twist.configuration.post_data_url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_default(),
+                twist.created_at.map_or(String::new(), format_utc),
+                (!twist.muted && !twist.mute_until.is_some_and(|until| now < until)).to_string(),
+                delivered.succeeded.to_string(),
+                delivered.failed.to_string(),
+                delivered.last_success.map_or(String::new(), format_utc),
+            ]
+        })
+        .collect();
+
+    match cmd.format {
+        ListFormat::Table => {
+            let mut widths = header.map(str::len);
+            for row in &rows {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+            let print_row = |cells: Vec<&str>| {
+                let padded: Vec<String> = cells
+                    .iter()
+                    .zip(widths)
+                    .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                    .collect();
+                println!("{}", padded.join("  ").trim_end());
+            };
+            print_row(header.to_vec());
+            for row in &rows {
+                print_row(row.iter().map(String::as_str).collect());
+            }
+        }
+        ListFormat::Csv => {
+            let quote = |cell: &str| {
+                if cell.contains([',', '"', '\n']) {
+                    format!("\"{}\"", cell.replace('"', "\"\""))
+                } else {
+                    cell.to_string()
+                }
+            };
+            println!("{}", header.join(","));
+            for row in &rows {
+                let cells: Vec<String> = row.iter().map(|cell| quote(cell)).collect();
+                println!("{}", cells.join(","));
+            }
+        }
+        ListFormat::Tsv => {
+            println!("{}", header.join("\t"));
+            for row in &rows {
+                let cells: Vec<String> = row.iter().map(|cell| cell.replace('\t', " ")).collect();
+                println!("{}", cells.join("\t"));
+            }
+        }
+        ListFormat::Json => {
+            let objects: Vec<serde_json::Map<String, serde_json::Value>> = rows
+                .iter()
+                .map(|row| {
+                    header
+                        .iter()
+                        .zip(row)
+                        .map(|(name, cell)| (name.to_string(), json!(cell)))
+                        .collect()
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&objects)?);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "dev-tools")]
+fn bench_render(cmd: BridgeCmdBenchRender) -> tide::Result<()> {
+    let sample = match cmd.variant.as_str() {
+        "uptime" => SAMPLE_UPTIME_ALERT,
+        "log" => SAMPLE_LOG_ALERT,
+        "budget" => SAMPLE_BUDGET_ALERT,
+        "invalid" => r#"{"incident": {}}"#,
+        other => {
+            eprintln!(
+                "unknown variant {:?}, expected uptime, log, budget or invalid",
+                other
+            );
+            std::process::exit(2);
+        }
+    };
+    let iterations = cmd.iterations.max(1);
+    let render = RenderOptions::default();
+
+    let mut timings = Vec::with_capacity(iterations);
+    let started = std::time::Instant::now();
+    for _ in 0..iterations {
+        let render_started = std::time::Instant::now();
+        std::hint::black_box(reply_to_json(sample.to_string(), &render));
+        timings.push(render_started.elapsed());
+    }
+    let total = started.elapsed();
+    timings.sort();
+
+    let percentile = |p: usize| timings[(timings.len() * p / 100).min(timings.len() - 1)];
+    println!(
+        "{} renders of {} in {:?}: {:.0}/s, p50 {:?}, p99 {:?}",
+        iterations,
+        cmd.variant,
+        total,
+        iterations as f64 / total.as_secs_f64(),
+        percentile(50),
+        percentile(99),
+    );
+
+    Ok(())
+}
+
+async fn serve(cmd: BridgeCmdServe) -> tide::Result<()> {
+    match cmd.log_format {
+        LogFormat::Text => tide::log::start(),
+        LogFormat::Json => {
+            if log::set_boxed_logger(Box::new(JsonLogger)).is_ok() {
+                log::set_max_level(log::LevelFilter::Info);
+            }
+        }
+    }
+
+    let server_name = match sanitize_server_name(&cmd.server_name) {
+        Ok(name) => name,
+        Err(err) => {
+            tide::log::error!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    if server_name != cmd.server_name {
+        tide::log::warn!(
+            "server name {:?} corrected to {:?}",
+            cmd.server_name,
+            server_name
+        );
+    }
+
+    let store_args = StoreArgs::new(&cmd.db, &cmd.store, &cmd.db_key, &cmd.db_key_file);
+    if let Some(pid) = store_args.served_by() {
+        tide::log::error!("{} is already served by pid {}", store_args.name(), pid);
+        std::process::exit(1);
+    }
+    if let Some(pidfile) = store_args.pidfile() {
+        if let Err(err) = std::fs::write(&pidfile, format!("{}\n", std::process::id())) {
+            tide::log::error!("failed to write {}: {}", pidfile, err);
+            std::process::exit(1);
+        }
+    }
+    let mut store = store_args.open(Some(&cmd));
+    store.load();
+    store
+        .list_twist_threads()
+        .iter()
+        .for_each(|x| tide::log::info!("> {} {}", x.secret_id, x.configuration.user_name));
+    let bind_addr = cmd.bind_addr.clone();
+    let render = match RenderOptions::from_serve(&cmd) {
+        Ok(render) => render,
+        Err(err) => {
+            tide::log::error!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    let policy = if cmd.follow_redirects {
+        reqwest::redirect::Policy::default()
+    } else {
+        reqwest::redirect::Policy::none()
+    };
+    let timeout = std::time::Duration::from_secs(cmd.request_timeout_secs.max(1));
+    let http = reqwest::Client::builder()
+        .redirect(policy)
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()?;
+    let sender: std::sync::Arc<dyn OutboundSender> = if cmd.dry_run {
+        std::sync::Arc::new(RecordingSender::default())
+    } else {
+        std::sync::Arc::new(ReqwestSender::new(
+            cmd.follow_redirects,
+            &cmd.allow_target_cidrs,
+            cmd.request_timeout_secs,
+        )?)
+    };
+    let state = State::new(&server_name, store, cmd, render, sender, http);
+
+    if let Some(url) = &state.opts.startup_notify_url {
+        if !url.starts_with("https://") {
+            tide::log::error!("--startup-notify-url must be an https url");
+            std::process::exit(2);
+        }
+    }
+
+    if let Some(primary) = &state.opts.replicate_from {
+        tide::log::info!("running as a standby of {}", primary);
+        let state = state.clone();
+        std::thread::spawn(move || replicate(state));
+    }
+
+    {
+        let state = state.clone();
+        std::thread::spawn(move || drain_on_shutdown(state));
+    }
+
+    if state.opts.stats_file.is_some() {
+        let state = state.clone();
+        std::thread::spawn(move || persist_stats(state));
+    }
+
+    let startup_notify_url = state.opts.startup_notify_url.clone();
+    let instance_label = state.opts.instance_label.clone();
+    let body_version = state.opts.twist_body_version;
+    let sender = state.sender.clone();
+    let dry_run = state.opts.dry_run;
+    let integrations = state.store.read().await.list_twist_threads().len();
+    let per_ip_limit = state.opts.per_ip_limit;
+    let mut app = tide::with_state(state);
+
+    app.with(resolve_client_ip);
+    if per_ip_limit.is_some() {
+        app.with(limit_per_ip);
+    }
+
+    app.with(tide::utils::After(|mut res: tide::Response| async {
+        if let Some(err) = res.error() {
+            res.set_body(err.to_string());
+            res.set_status(500);
+        }
+        Ok(res)
+    }));
+
+    app.at("/twist/on_configure").get(twist_configure);
+    app.at("/twist/outgoing").post(twist_outgoing);
+    app.at("/twist/status/:id").get(twist_status);
+    app.at("/healthz").get(healthz);
+    app.at("/ready").get(ready);
+    app.at("/metrics").get(metrics);
+    app.at("/gcp/webhooks/:id").post(gcp_webhook);
+    app.at("/admin/integrations").get(admin_list_integrations);
+    app.at("/admin/maintenance")
+        .get(admin_get_maintenance)
+        .put(admin_set_maintenance);
+    app.at("/admin/integrations/:id/mute")
+        .post(admin_mute_integration);
+    app.at("/admin/integrations/:id/unmute")
+        .post(admin_unmute_integration);
+    app.at("/admin/integrations/:id")
+        .post(admin_update_integration);
+    let bind_addr = match bind_addr.strip_prefix("unix:") {
+        Some(path) => {
+            if let Err(err) = remove_socket(path) {
+                tide::log::error!("{}", err);
+                std::process::exit(1);
+            }
+            format!("http+unix://{}", path)
+        }
+        None => bind_addr,
+    };
+    let mut listener = app.bind(bind_addr).await?;
+
+    if let Some(url) = startup_notify_url.as_ref().filter(|_| !dry_run) {
+        let content = format!(
+            "🟢 Bridge started, {} integrations loaded, version {}.",
+            integrations,
+            env!("CARGO_PKG_VERSION")
+        );
+        let body = body_version.body(with_instance_label(content, &instance_label), false);
+        match sender.post(url, None, &body).await {
+            Ok(status) if is_success(status) => {}
+            Ok(status) => tide::log::warn!("startup notice answered with {}", status),
+            Err(err) => tide::log::warn!("failed to post startup notice: {}", err),
+        }
+    }
+
+    listener.accept().await?;
+
+    tide::log::info!("byee!");
+
+    Ok(())
+}
+
+/// The address a request came from, as resolved by `resolve_client_ip`.
+#[derive(Debug, Clone, Copy)]
+struct ClientIp(std::net::IpAddr);
+
+fn client_ip<S>(req: &Request<S>) -> Option<std::net::IpAddr> {
+    req.ext::<ClientIp>().map(|client| client.0)
+}
+
+/// Walks X-Forwarded-For back from the peer, past every hop that is one of
+/// the `--trusted-proxies`, to the first address that is not. Headers sent by
+/// untrusted peers are ignored so clients cannot spoof their address.
+fn forwarded_client(req: &Request<State>, peer: std::net::IpAddr) -> std::net::IpAddr {
+    let trusted = &req.state().opts.trusted_proxies;
+    let is_trusted = |ip: &std::net::IpAddr| trusted.iter().any(|cidr| cidr.contains(*ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    let hops: Vec<std::net::IpAddr> = match req.header("X-Forwarded-For") {
+        Some(values) => values
+            .iter()
+            .flat_map(|value| value.as_str().split(','))
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect(),
+        None => return peer,
+    };
+    hops.into_iter()
+        .rev()
+        .find(|hop| !is_trusted(hop))
+        .unwrap_or(peer)
+}
+
+/// Records the client ip of every request as a `ClientIp` extension.
+fn resolve_client_ip<'a>(
+    mut req: Request<State>,
+    next: tide::Next<'a, State>,
+) -> std::pin::Pin<Box<dyn std::future::Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        let peer = req
+            .peer_addr()
+            .and_then(|addr| addr.parse::<std::net::SocketAddr>().ok())
+            .map(|addr| addr.ip());
+        if let Some(peer) = peer {
+            let ip = forwarded_client(&req, peer);
+            req.set_ext(ClientIp(ip));
+        }
+        Ok(next.run(req).await)
+    })
+}
+
+/// A request counted against its source ip until dropped.
+struct InFlightSlot {
+    counts: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<std::net::IpAddr, usize>>>,
+    ip: std::net::IpAddr,
+}
+
+impl InFlightSlot {
+    fn acquire(state: &State, ip: std::net::IpAddr, limit: usize) -> Option<Self> {
+        let mut counts = state.in_flight.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            counts: state.in_flight.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// A webhook or configure the shutdown waits for until dropped.
+struct BusySlot(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+impl BusySlot {
+    /// `None` once the bridge is closing for shutdown.
+    fn acquire(state: &State) -> Option<Self> {
+        use std::sync::atomic::Ordering;
+
+        // counted before checking, so the shutdown either sees this request
+        // or this request sees the shutdown
+        state.busy.fetch_add(1, Ordering::SeqCst);
+        let slot = Self(state.busy.clone());
+        if state.closing.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(slot)
+    }
+}
+
+impl Drop for BusySlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Answers 429 to a source ip that already has `--per-ip-limit` requests in
+/// flight.
+fn limit_per_ip<'a>(
+    req: Request<State>,
+    next: tide::Next<'a, State>,
+) -> std::pin::Pin<Box<dyn std::future::Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        let (ip, limit) = match (client_ip(&req), req.state().opts.per_ip_limit) {
+            (Some(ip), Some(limit)) => (ip, limit),
+            _ => return Ok(next.run(req).await),
+        };
+        let _slot = match InFlightSlot::acquire(req.state(), ip, limit) {
+            Some(slot) => slot,
+            None => {
+                tide::log::warn!("too many requests in flight from {}", ip);
+                return Ok(tide::Response::new(StatusCode::TooManyRequests));
+            }
+        };
+        Ok(next.run(req).await)
+    })
+}
+
+/// Reduces a user supplied server name to a bare `host[:port]`, stripping any
+/// scheme and trailing slashes that would otherwise end up in webhook urls.
+fn sanitize_server_name(raw: &str) -> Result<String, String> {
+    let trimmed = raw.trim();
+    let without_scheme = match trimmed.find("://") {
+        Some(idx) => &trimmed[idx + 3..],
+        None => trimmed,
+    };
+    let host = without_scheme.trim_end_matches('/');
+
+    let invalid = || format!("invalid server name {:?}, expected host[:port]", raw);
+    if host.is_empty()
+        || host
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '/' | '?' | '#' | '@'))
+    {
+        return Err(invalid());
+    }
+    if !host.ends_with(']') {
+        if let Some((name, port)) = host.rsplit_once(':') {
+            if name.is_empty() || port.parse::<u16>().is_err() {
+                return Err(invalid());
+            }
+        }
+    }
+
+    Ok(host.to_string())
+}
+
+impl GoogleWebhookPayload {
+    /// Whether this notification reports a newly opened uptime incident.
+    fn is_open(&self) -> bool {
+        match self {
+            GoogleWebhookPayload::GoogleUptimeAlert(alert) => alert.incident.state == "open",
+            GoogleWebhookPayload::GoogleLogAlert(_) => false,
+            GoogleWebhookPayload::GoogleBudgetAlert(_) => false,
+        }
+    }
+
+    /// Identifies repeats of the same notification. Uptime incidents include
+    /// their state so a resolution is never mistaken for a repeat of the open.
+    fn dedup_key(&self, fingerprint: &[String]) -> String {
+        let incident = self.fingerprint(fingerprint);
+        match self {
+            GoogleWebhookPayload::GoogleUptimeAlert(alert) => {
+                format!("{}#{}", incident, alert.incident.state)
+            }
+            GoogleWebhookPayload::GoogleLogAlert(_) => incident,
+            GoogleWebhookPayload::GoogleBudgetAlert(alert) => format!(
+                "{}#{:?}#{:?}",
+                incident, alert.alert_threshold_exceeded, alert.forecast_threshold_exceeded
+            ),
+        }
+    }
+
+    /// Correlates the notifications of one incident: the configured `fields`
+    /// joined, or the incident url without any. Budgets have no url and are
+    /// told apart by name and billing period.
+    fn fingerprint(&self, fields: &[String]) -> String {
+        if fields.is_empty() {
+            return match self {
+                GoogleWebhookPayload::GoogleBudgetAlert(alert) => format!(
+                    "budget:{}@{}",
+                    alert.budget_display_name,
+                    alert.cost_interval_start.as_deref().unwrap_or_default()
+                ),
+                _ => self.incident_url().unwrap_or_default().to_string(),
+            };
+        }
+        let resources = match self {
+            GoogleWebhookPayload::GoogleLogAlert(alert) => alert.incident.affected_resources(),
+            _ => Vec::new(),
+        };
+        fields
+            .iter()
+            .map(|field| match field.as_str() {
+                "url" => self.incident_url().unwrap_or_default().to_string(),
+                "policy_name" => self.policy_name().to_string(),
+                "severity" => self.severity().unwrap_or_default().to_string(),
+                "resource_type" => resources
+                    .iter()
+                    .map(|resource| resource.resource_type.as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                label => resources
+                    .iter()
+                    .filter_map(|resource| {
+                        resource.label(label.strip_prefix("resource.").unwrap_or(label))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+            })
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+
+    fn policy_name(&self) -> &str {
+        match self {
+            GoogleWebhookPayload::GoogleUptimeAlert(alert) => &alert.incident.policy_name,
+            GoogleWebhookPayload::GoogleLogAlert(alert) => &alert.incident.policy_name,
+            GoogleWebhookPayload::GoogleBudgetAlert(alert) => &alert.budget_display_name,
+        }
+    }
+
+    fn variant_name(&self) -> &'static str {
+        match self {
+            GoogleWebhookPayload::GoogleUptimeAlert(_) => "GoogleUptimeAlert",
+            GoogleWebhookPayload::GoogleLogAlert(_) => "GoogleLogAlert",
+            GoogleWebhookPayload::GoogleBudgetAlert(_) => "GoogleBudgetAlert",
+        }
+    }
+
+    /// The alerting policy's severity, e.g. `Critical` or `Warning`.
+    fn severity(&self) -> Option<&str> {
+        match self {
+            GoogleWebhookPayload::GoogleUptimeAlert(alert) => alert.incident.severity.as_deref(),
+            GoogleWebhookPayload::GoogleLogAlert(alert) => alert.incident.severity.as_deref(),
+            GoogleWebhookPayload::GoogleBudgetAlert(_) => None,
+        }
+    }
+
+    /// Image of the metric behind the incident, when the payload carries one.
+    fn chart_url(&self) -> Option<&str> {
+        match self {
+            GoogleWebhookPayload::GoogleUptimeAlert(alert) => alert.incident.chart_url.as_deref(),
+            GoogleWebhookPayload::GoogleLogAlert(alert) => alert.incident.chart_url.as_deref(),
+            GoogleWebhookPayload::GoogleBudgetAlert(_) => None,
+        }
+    }
+
+    fn incident_url(&self) -> Option<&str> {
+        match self {
+            GoogleWebhookPayload::GoogleUptimeAlert(alert) => Some(&alert.incident.url),
+            GoogleWebhookPayload::GoogleLogAlert(alert) => Some(&alert.incident.url),
+            GoogleWebhookPayload::GoogleBudgetAlert(_) => None,
+        }
+    }
+}
+
+/// Twist content for a GCP notification, along with the parsed payload when
+/// it matched one of the known shapes.
+struct RenderedAlert {
+    content: String,
+    payload: Option<GoogleWebhookPayload>,
+    pubsub: Option<PubSubMessage>,
+    parse_error: Option<String>,
+}
+
+/// A Pub/Sub push subscription delivery, wrapping the alert as base64 `data`.
+#[derive(Debug, Deserialize)]
+struct PubSubPush {
+    message: PubSubMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct PubSubMessage {
+    data: String,
+    #[serde(default)]
+    attributes: std::collections::HashMap<String, String>,
+    /// stable across redeliveries of the same message
+    #[serde(default, rename = "messageId", alias = "message_id")]
+    message_id: Option<String>,
+}
+
+/// Returns the alert json inside a Pub/Sub push envelope along with the
+/// envelope's message, or the body untouched when it is not an envelope.
+fn unwrap_pubsub(json: String) -> (String, Option<PubSubMessage>) {
+    use base64::Engine as _;
+
+    let push = match serde_json::from_str::<PubSubPush>(&json) {
+        Ok(push) => push,
+        Err(_) => return (json, None),
+    };
+    match base64::engine::general_purpose::STANDARD
+        .decode(&push.message.data)
+        .ok()
+        .and_then(|data| String::from_utf8(data).ok())
+    {
+        Some(inner) => (inner, Some(push.message)),
+        None => (json, None),
+    }
+}
+
+fn reply_to_json(json: String, opts: &RenderOptions) -> RenderedAlert {
+    let (json, pubsub) = unwrap_pubsub(json);
+    let json = unwrap_single_alert(json);
+    match serde_json::from_str::<GoogleWebhookPayload>(&json) {
+        Ok(payload) => {
+            if opts.warn_unknown_fields {
+                for field in unknown_fields(&json, &payload) {
+                    tide::log::debug!("gcp payload field {} is not modelled", field);
+                }
+            }
+            RenderedAlert {
+                content: render_payload(&payload, opts),
+                payload: Some(payload),
+                pubsub,
+                parse_error: None,
+            }
+        }
+        Err(err) => {
+            let diagnosis = diagnose_payload(&json, err);
+            let echoed = if opts.compact_parse_failures {
+                None
+            } else {
+                serde_json::from_str::<serde_json::Value>(&json)
+                    .ok()
+                    .and_then(|value| serde_json::to_string_pretty(&value).ok())
+            };
+            let header = format!("Failed to parse due to {}:", diagnosis);
+            let budget = opts
+                .content_limit
+                .saturating_sub(header.chars().count() + "\n\n```\n\n```".len());
+            RenderedAlert {
+                content: format!(
+                    "{header}\n\n```\n{payload}\n```",
+                    header = header,
+                    payload = truncate_chars(echoed.as_deref().unwrap_or(&json), budget)
+                ),
+                payload: None,
+                pubsub,
+                parse_error: Some(diagnosis),
+            }
+        }
+    }
+}
+
+/// Some GCP exports wrap a lone alert in an array, which is unwrapped here.
+/// Longer arrays are batches, see `replies_to_json`.
+fn unwrap_single_alert(json: String) -> String {
+    match serde_json::from_str::<Vec<serde_json::Value>>(&json) {
+        Ok(mut alerts) if alerts.len() == 1 => alerts.remove(0).to_string(),
+        _ => json,
+    }
+}
+
+/// Renders every alert of a delivery. GCP may batch alerts as a json array,
+/// or as bare incidents in an `incidents` array, whose alerts are each
+/// rendered on their own, to be merged by `batch_alerts` once deduplicated.
+fn replies_to_json(json: String, opts: &RenderOptions) -> Vec<RenderedAlert> {
+    #[derive(Deserialize)]
+    struct Incidents {
+        incidents: Vec<serde_json::Value>,
+    }
+
+    let (json, pubsub) = unwrap_pubsub(json);
+    let batch = match serde_json::from_str::<Vec<serde_json::Value>>(&json) {
+        Ok(batch) if !batch.is_empty() => batch,
+        _ => match serde_json::from_str::<Incidents>(&json) {
+            Ok(wrapped) if !wrapped.incidents.is_empty() => wrapped
+                .incidents
+                .into_iter()
+                .map(|incident| json!({ "incident": incident }))
+                .collect(),
+            _ => {
+                let mut reply = reply_to_json(json, opts);
+                reply.pubsub = pubsub;
+                return vec![reply];
+            }
+        },
+    };
+
+    let mut replies: Vec<RenderedAlert> = batch
+        .into_iter()
+        .map(|alert| reply_to_json(alert.to_string(), opts))
+        .collect();
+    if let Some(first) = replies.first_mut() {
+        first.pubsub = pubsub;
+    }
+    replies
+}
+
+/// Merges the alerts of a delivery that got past dedup into the messages
+/// `--batch-mode combined` (all of them) or `--group-batches` (those of the
+/// same policy) ask for, each with the dedup keys of the alerts it carries.
+/// Only alerts routed to the same thread are merged, so that the first
+/// incident a merged alert is posted by routes it like the others.
+fn batch_alerts(
+    alerts: Vec<(RenderedAlert, Option<String>)>,
+    twist: &TwistIntegration,
+    opts: &RenderOptions,
+) -> Vec<(RenderedAlert, Vec<String>)> {
+    let combined = opts.batch_mode == BatchMode::Combined;
+    if !combined && !opts.group_batches {
+        return alerts
+            .into_iter()
+            .map(|(reply, key)| (reply, key.into_iter().collect()))
+            .collect();
+    }
+
+    type Group = ((String, Option<String>), Vec<RenderedAlert>, Vec<String>);
+    let mut groups: Vec<Group> = Vec::new();
+    for (reply, key) in alerts {
+        let payload = reply.payload.as_ref();
+        let url = twist
+            .post_url_for(
+                payload.and_then(|p| p.severity()),
+                payload.map(|p| p.policy_name()),
+            )
+            .to_string();
+        let policy = payload
+            .map(|p| p.policy_name().to_string())
+            .filter(|_| !combined);
+        let route = (url, policy);
+        // alerts that failed to parse have no policy to be grouped by
+        match groups
+            .iter_mut()
+            .find(|(found, _, _)| *found == route && (combined || route.1.is_some()))
+        {
+            Some((_, group, keys)) => {
+                group.push(reply);
+                keys.extend(key);
+            }
+            None => groups.push((route, vec![reply], key.into_iter().collect())),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|((_, policy), group, keys)| {
+            let heading = match policy {
+                Some(policy) => format!("of {}", policy),
+                None => "in this delivery".to_string(),
+            };
+            (merge_alerts(group, &heading, opts), keys)
+        })
+        .collect()
+}
+
+/// Merges `group` into one message headed e.g. `3 incidents of api errors:`,
+/// keeping the payload of its first alert.
+fn merge_alerts(
+    mut group: Vec<RenderedAlert>,
+    heading: &str,
+    opts: &RenderOptions,
+) -> RenderedAlert {
+    if group.len() == 1 {
+        return group.remove(0);
+    }
+    let incidents: Vec<String> = group.iter().map(|reply| reply.content.clone()).collect();
+    let mut merged = group.remove(0);
+    merged.content = truncate_chars(
+        &format!(
+            "{} incidents {}:\n\n{}",
+            incidents.len(),
+            heading,
+            incidents.join("\n\n---\n\n")
+        ),
+        opts.content_limit,
+    );
+    merged
+}
+
+/// Paths of the fields in `json` that were dropped when parsing it as
+/// `payload`, found by comparing it against the payload serialized back.
+fn unknown_fields(json: &str, payload: &GoogleWebhookPayload) -> Vec<String> {
+    fn walk(path: &str, raw: &serde_json::Value, known: &serde_json::Value, out: &mut Vec<String>) {
+        match (raw, known) {
+            (serde_json::Value::Object(raw), serde_json::Value::Object(known)) => {
+                for (key, value) in raw {
+                    let field = if path.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+                    match known.get(key) {
+                        Some(known) => walk(&field, value, known, out),
+                        None => out.push(field),
+                    }
+                }
+            }
+            (serde_json::Value::Array(raw), serde_json::Value::Array(known)) => {
+                for (idx, (raw, known)) in raw.iter().zip(known).enumerate() {
+                    walk(&format!("{}[{}]", path, idx), raw, known, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut out = Vec::new();
+    if let (Ok(raw), Ok(known)) = (
+        serde_json::from_str::<serde_json::Value>(json),
+        serde_json::to_value(payload),
+    ) {
+        walk("", &raw, &known, &mut out);
+    }
+    out
+}
+
+/// Explains why `json` matched none of the `GoogleWebhookPayload` variants.
+///
+/// The untagged enum only reports that no variant matched, so each variant is
+/// tried on its own and the error of the one that got furthest is reported.
+fn diagnose_payload(json: &str, err: serde_json::Error) -> String {
+    fn attempt<T: serde::de::DeserializeOwned>(
+        variant: &'static str,
+        json: &str,
+    ) -> Option<(usize, String)> {
+        let deserializer = &mut serde_json::Deserializer::from_str(json);
+        serde_path_to_error::deserialize::<_, T>(deserializer)
+            .err()
+            .map(|err| {
+                let depth = err.path().iter().count();
+                let message = format!(
+                    "looked most like {} but {} was invalid: {}",
+                    variant,
+                    err.path(),
+                    err.inner()
+                );
+                (depth, message)
+            })
+    }
+
+    if err.classify() != serde_json::error::Category::Data {
+        return err.to_string();
+    }
+    [
+        attempt::<GoogleLogAlert>("GoogleLogAlert", json),
+        attempt::<GoogleUptimeAlert>("GoogleUptimeAlert", json),
+        attempt::<GoogleBudgetAlert>("GoogleBudgetAlert", json),
+    ]
+    .into_iter()
+    .flatten()
+    .max_by_key(|(depth, _)| *depth)
+    .map_or(err.to_string(), |(_, message)| message)
+}
+
+/// Formatting knobs applied when rendering alerts.
+struct RenderOptions {
+    /// replaces the built-in layout of every alert, see `template_fields`
+    template: Option<String>,
+    /// appended to every alert, see `render_footer`
+    footer: Option<String>,
+    /// replaces the ✅ message for resolved uptime incidents, see
+    /// `render_resolved`
+    resolved_template: Option<String>,
+    /// longest message, in characters, we try to keep posts under
+    content_limit: usize,
+    /// longest log alert documentation, see `truncate_docs`
+    docs_limit: usize,
+    /// echo unparseable payloads as received rather than pretty-printed
+    compact_parse_failures: bool,
+    /// applied to every posted message, on top of per-integration patterns
+    redactions: Vec<regex::Regex>,
+    /// log, at debug, payload fields our structs do not capture
+    warn_unknown_fields: bool,
+    /// post one message per policy for batched alerts
+    group_batches: bool,
+    /// post batched alerts separately or as one message
+    batch_mode: BatchMode,
+}
+
+const DEFAULT_CONTENT_LIMIT: usize = 8000;
+const DEFAULT_DOCS_LIMIT: usize = 8000;
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            template: None,
+            footer: None,
+            resolved_template: None,
+            content_limit: DEFAULT_CONTENT_LIMIT,
+            docs_limit: DEFAULT_DOCS_LIMIT,
+            compact_parse_failures: false,
+            redactions: Vec::new(),
+            warn_unknown_fields: false,
+            group_batches: false,
+            batch_mode: BatchMode::Separate,
+        }
+    }
+}
+
+impl RenderOptions {
+    pub fn from_serve(cmd: &BridgeCmdServe) -> Result<Self, String> {
+        let read = |path: &String| {
+            std::fs::read_to_string(path)
+                .map_err(|err| format!("failed to read template {}: {}", path, err))
+        };
+
+        Ok(Self {
+            template: cmd.template_file.as_ref().map(read).transpose()?,
+            footer: cmd.footer_template_file.as_ref().map(read).transpose()?,
+            resolved_template: cmd.resolved_template_file.as_ref().map(read).transpose()?,
+            content_limit: cmd.content_limit,
+            docs_limit: cmd.docs_limit,
+            compact_parse_failures: cmd.compact_parse_failures,
+            redactions: compile_patterns(&cmd.redact)?,
+            warn_unknown_fields: cmd.warn_unknown_fields,
+            group_batches: cmd.group_batches,
+            batch_mode: cmd.batch_mode,
+        })
+    }
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<Vec<regex::Regex>, String> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            regex::Regex::new(pattern)
+                .map_err(|err| format!("invalid redaction pattern {:?}: {}", pattern, err))
+        })
+        .collect()
+}
+
+/// Replaces every match of `patterns` in `content` with `[redacted]`.
+fn redact<'a>(content: &str, patterns: impl Iterator<Item = &'a regex::Regex>) -> String {
+    patterns.fold(content.to_string(), |content, pattern| {
+        pattern.replace_all(&content, "[redacted]").into_owned()
+    })
+}
+
+/// Cuts `text` down to at most `max` characters, marking the cut with `…`.
+fn truncate_chars(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Cuts documentation longer than `max` characters, pointing at the incident
+/// for the rest with a link that counts towards `max`.
+fn truncate_docs(docs: &str, incident_url: &str, max: usize) -> String {
+    if docs.chars().count() <= max {
+        return docs.to_string();
+    }
+    let link = format!("\n\n[full documentation]({})", incident_url);
+    format!(
+        "{}{}",
+        truncate_chars(docs, max.saturating_sub(link.chars().count())),
+        link
+    )
+}
+
+/// Substitutes each `{name}` in `template` with its value.
+fn fill_template(template: &str, fields: &[(&str, String)]) -> String {
+    fields
+        .iter()
+        .fold(template.to_string(), |out, (name, value)| {
+            out.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+/// When an incident started and ended, e.g. `\n\nStarted 2024-05-01T13:37:00Z`,
+/// empty when the payload says neither.
+fn incident_times(started_at: Option<u64>, ended_at: Option<u64>) -> String {
+    match (started_at, ended_at) {
+        (Some(started), Some(ended)) => format!(
+            "\n\nStarted {}, ended {}",
+            format_utc(started),
+            format_utc(ended)
+        ),
+        (Some(started), None) => format!("\n\nStarted {}", format_utc(started)),
+        (None, Some(ended)) => format!("\n\nEnded {}", format_utc(ended)),
+        (None, None) => String::new(),
+    }
+}
+
+/// Renders a duration as the two most significant units, e.g. `1h 5m`.
+fn format_duration(secs: u64) -> String {
+    let (hours, minutes, seconds) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Renders a resolved uptime incident with the resolved template, which can
+/// use `{policy_name}`, `{url}`, `{summary}`, `{started_at}`, `{ended_at}`
+/// (epoch seconds) and `{duration}` (e.g. `12m`, empty when unknown).
+fn render_resolved(template: &str, incident: &GoogleUptimeIncident) -> String {
+    fill_template(template, &uptime_fields(incident))
+}
+
+fn uptime_fields(incident: &GoogleUptimeIncident) -> Vec<(&'static str, String)> {
+    let epoch = |at: Option<u64>| at.map_or(String::new(), |at| at.to_string());
+    let duration = match (incident.started_at, incident.ended_at) {
+        (Some(started), Some(ended)) => format_duration(ended.saturating_sub(started)),
+        _ => String::new(),
+    };
+    vec![
+        ("policy_name", incident.policy_name.clone()),
+        ("url", incident.url.clone()),
+        ("summary", incident.summary.clone()),
+        ("started_at", epoch(incident.started_at)),
+        ("ended_at", epoch(incident.ended_at)),
+        ("duration", duration),
+    ]
+}
+
+/// Renders the `--footer-template-file`, or the integration's own footer, with
+/// the `template_fields` of the alert plus `{runbook_url}` and `{install_id}`.
+fn render_footer(
+    template: &str,
+    payload: &GoogleWebhookPayload,
+    twist: &TwistIntegration,
+    opts: &RenderOptions,
+) -> String {
+    let mut fields = template_fields(payload, opts.docs_limit);
+    fields.push(("runbook_url", twist.runbook_url.clone().unwrap_or_default()));
+    fields.push(("install_id", twist.configuration.install_id.clone()));
+    fill_template(template, &fields)
+}
+
+/// Placeholders of the `--template-file`. Every alert has `{state_emoji}`,
+/// `{policy_name}`, `{url}` and `{severity}`, on top of which
+/// - uptime alerts have `{state}`, `{summary}`, `{started_at}`, `{ended_at}`
+///   and `{duration}`, as in `render_resolved`
+/// - log alerts have `{service}`, `{resource_url}`, `{labels}` and `{docs}`
+/// - budget alerts have `{cost}`, `{budget}`, `{currency}` and `{threshold}`
+///   (percent), with `{policy_name}` the budget name and `{url}` empty
+///
+/// Placeholders an alert type lacks are left as written.
+fn template_fields(
+    payload: &GoogleWebhookPayload,
+    docs_limit: usize,
+) -> Vec<(&'static str, String)> {
+    let mut fields = vec![(
+        "severity",
+        payload.severity().unwrap_or_default().to_string(),
+    )];
+    match payload {
+        GoogleWebhookPayload::GoogleUptimeAlert(alert) => {
+            let open = alert.incident.state == "open";
+            fields.push(("state_emoji", String::from(if open { "🚨" } else { "✅" })));
+            fields.push(("state", alert.incident.state.clone()));
+            fields.extend(uptime_fields(&alert.incident));
+        }
+        GoogleWebhookPayload::GoogleLogAlert(alert) => {
+            let resources = alert.incident.affected_resources();
+            fields.extend([
+                ("state_emoji", String::from("🚨")),
+                ("policy_name", alert.incident.policy_name.clone()),
+                ("url", alert.incident.url.clone()),
+                (
+                    "service",
+                    resources
+                        .first()
+                        .and_then(|resource| resource.label("container_name"))
+                        .unwrap_or("unknown")
+                        .to_string(),
+                ),
+                (
+                    "labels",
+                    resources
+                        .first()
+                        .map_or(String::new(), |resource| resource.label_list()),
+                ),
+                (
+                    "resource_url",
+                    resources
+                        .first()
+                        .and_then(|resource| console_resource_url(resource))
+                        .unwrap_or_default(),
+                ),
+                (
+                    "docs",
+                    truncate_docs(
+                        &alert.incident.documentation.as_markdown(),
+                        &alert.incident.url,
+                        docs_limit,
+                    ),
+                ),
+            ]);
+        }
+        GoogleWebhookPayload::GoogleBudgetAlert(alert) => {
+            let threshold = alert
+                .alert_threshold_exceeded
+                .or(alert.forecast_threshold_exceeded);
+            fields.extend([
+                ("state_emoji", String::from("💰")),
+                ("policy_name", alert.budget_display_name.clone()),
+                ("url", String::new()),
+                ("cost", format!("{:.2}", alert.cost_amount)),
+                ("budget", format!("{:.2}", alert.budget_amount)),
+                ("currency", alert.currency_code.clone()),
+                (
+                    "threshold",
+                    threshold.map_or(String::new(), |t| (t * 100.0).round().to_string()),
+                ),
+            ]);
+        }
+    }
+    fields
+}
+
+/// Best-effort Cloud Console link to the resource an alert fired on, for the
+/// resource types whose labels identify a console page.
+fn console_resource_url(resource: &GoogleResource) -> Option<String> {
+    let label = |name: &str| resource.label(name);
+    let project = label("project_id")?;
+
+    let path = match resource.resource_type.as_str() {
+        "gce_instance" => format!(
+            "compute/instancesDetail/zones/{}/instances/{}",
+            label("zone")?,
+            label("instance_id")?
+        ),
+        "k8s_container" | "k8s_pod" => format!(
+            "kubernetes/pod/{}/{}/{}/{}/details",
+            label("location")?,
+            label("cluster_name")?,
+            label("namespace_name")?,
+            label("pod_name")?
+        ),
+        "k8s_cluster" => format!(
+            "kubernetes/clusters/details/{}/{}/details",
+            label("location")?,
+            label("cluster_name")?
+        ),
+        "cloud_run_revision" => format!(
+            "run/detail/{}/{}/metrics",
+            label("location")?,
+            label("service_name")?
+        ),
+        "cloud_function" => format!(
+            "functions/details/{}/{}",
+            label("region")?,
+            label("function_name")?
+        ),
+        _ => return None,
+    };
+
+    Some(format!(
+        "https://console.cloud.google.com/{}?project={}",
+        path, project
+    ))
+}
+
+fn render_payload(payload: &GoogleWebhookPayload, opts: &RenderOptions) -> String {
+    render_payload_within(payload, opts, opts.content_limit)
+}
+
+/// Renders `payload` with the documentation of log alerts cut short enough
+/// for the message to fit in `limit` characters, its link to the rest kept.
+fn render_payload_within(
+    payload: &GoogleWebhookPayload,
+    opts: &RenderOptions,
+    limit: usize,
+) -> String {
+    let resolved = matches!(payload, GoogleWebhookPayload::GoogleUptimeAlert(alert) if alert.incident.state != "open");
+    if let Some(template) = opts
+        .template
+        .as_ref()
+        .filter(|_| !(resolved && opts.resolved_template.is_some()))
+    {
+        let rest = fill_template(
+            &template.replace("{docs}", ""),
+            &template_fields(payload, opts.docs_limit),
+        );
+        let docs_budget = opts
+            .docs_limit
+            .min(limit.saturating_sub(rest.chars().count()));
+        return fill_template(template, &template_fields(payload, docs_budget));
+    }
+
+    match payload {
+        GoogleWebhookPayload::GoogleLogAlert(alert) => {
+            let resources = alert.incident.affected_resources();
+            let svc = resources
+                .first()
+                .and_then(|resource| resource.label("container_name"))
+                .map_or("unknown", |name| name);
+
+            let resource_link = resources
+                .first()
+                .and_then(|resource| console_resource_url(resource))
+                .map_or(String::new(), |url| format!(" [resource]({})", url));
+
+            let resource_list = if resources.len() > 1 {
+                let lines: Vec<String> = resources
+                    .iter()
+                    .map(|resource| format!("- {}", resource.describe()))
+                    .collect();
+                format!("\n\nAffected resources:\n{}", lines.join("\n"))
+            } else {
+                resources
+                    .first()
+                    .map(|resource| resource.label_list())
+                    .filter(|labels| !labels.is_empty())
+                    .map_or(String::new(), |labels| format!("\n\n`{}`", labels))
+            };
+
+            let head = format!(
+                "🚨 {alert} on {name} [incident]({incident_url}){resource_link}{resource_list}{times}\n\n",
+                alert = alert.incident.policy_name,
+                name = svc,
+                incident_url = alert.incident.url,
+                resource_link = resource_link,
+                resource_list = resource_list,
+                times = incident_times(alert.incident.started_at, alert.incident.ended_at),
+            );
+            let docs_budget = opts
+                .docs_limit
+                .min(limit.saturating_sub(head.chars().count()));
+            head + &truncate_docs(
+                &alert.incident.documentation.as_markdown(),
+                &alert.incident.url,
+                docs_budget,
+            )
+        }
+        GoogleWebhookPayload::GoogleUptimeAlert(alert) if alert.incident.state != "open" => {
+            match &opts.resolved_template {
+                Some(template) => render_resolved(template, &alert.incident),
+                None => format!(
+                    "✅ {alert} [incident]({incident_url})\n\n{summary}{times}",
+                    alert = alert.incident.policy_name,
+                    incident_url = alert.incident.url,
+                    summary = alert.incident.summary,
+                    times = incident_times(alert.incident.started_at, alert.incident.ended_at),
+                ),
+            }
+        }
+        GoogleWebhookPayload::GoogleUptimeAlert(alert) => format!(
+            "🚨 {alert} [incident]({incident_url})\n\n{summary}{times}",
+            alert = alert.incident.policy_name,
+            incident_url = alert.incident.url,
+            summary = alert.incident.summary,
+            times = incident_times(alert.incident.started_at, None),
+        ),
+        GoogleWebhookPayload::GoogleBudgetAlert(alert) => {
+            let spent = format!(
+                "{:.2} {} of {:.2} {}",
+                alert.cost_amount, alert.currency_code, alert.budget_amount, alert.currency_code
+            );
+            match (
+                alert.alert_threshold_exceeded,
+                alert.forecast_threshold_exceeded,
+            ) {
+                (Some(threshold), _) => format!(
+                    "💰 Budget {} exceeded {}% ({})",
+                    alert.budget_display_name,
+                    (threshold * 100.0).round(),
+                    spent
+                ),
+                (None, Some(threshold)) => format!(
+                    "💰 Budget {} forecast to exceed {}% ({})",
+                    alert.budget_display_name,
+                    (threshold * 100.0).round(),
+                    spent
+                ),
+                (None, None) => format!("💰 Budget {} at {}", alert.budget_display_name, spent),
+            }
+        }
+    }
+}
+
+async fn twist_content(req: &mut Request<State>) -> Option<Vec<RenderedAlert>> {
+    match req.body_string().await {
+        Ok(json) => {
+            let replies = replies_to_json(json, &req.state().render);
+            for err in replies
+                .iter()
+                .filter_map(|reply| reply.parse_error.as_ref())
+            {
+                tide::log::warn!("unrecognised gcp payload: {}", err);
+            }
+            Some(replies)
+        }
+        Err(_) => None,
+    }
+}
+
+async fn gcp_webhook(mut req: Request<State>) -> tide::Result {
+    Metrics::inc(&req.state().metrics.webhooks_received);
+    let _busy = match BusySlot::acquire(req.state()) {
+        Some(busy) => busy,
+        None => return Ok(tide::Response::new(StatusCode::ServiceUnavailable)),
+    };
+    let allowed = &req.state().opts.gcp_allowed_cidrs;
+    if !allowed.is_empty() {
+        let ip = client_ip(&req);
+        if !ip.is_some_and(|ip| allowed.iter().any(|cidr| cidr.contains(ip))) {
+            tide::log::warn!("rejecting gcp webhook from {:?}, not an allowed source", ip, {
+                event: "source_rejected"
+            });
+            return Ok(tide::Response::new(StatusCode::Forbidden));
+        }
+    }
+    if let Some(res) = media_type_rejection(&req, &req.state().opts.gcp_content_type) {
+        return Ok(res);
+    }
+    let replies = match twist_content(&mut req).await {
+        Some(replies) => replies,
+        None => return Ok("OK".into()),
+    };
+    let state = req.state().clone();
+    let url_id = req.param("id")?.to_string();
+    if !is_valid_install_id(&url_id) {
+        let mut res = tide::Response::new(StatusCode::BadRequest);
+        res.set_body("invalid integration id");
+        return Ok(res);
+    }
+
+    let twist = {
+        let store = match lock_store(&state).await {
+            Some(store) => store,
+            None => {
+                tide::log::warn!("store lock timed out, rejecting alert for {}", url_id, {
+                    install_id: url_id,
+                    event: "store_locked"
+                });
+                return Ok(tide::Response::new(StatusCode::ServiceUnavailable));
+            }
+        };
+        let routed = state
+            .opts
+            .pubsub_route_attribute
+            .as_ref()
+            .and_then(|attribute| {
+                let pubsub = replies.iter().find_map(|reply| reply.pubsub.as_ref())?;
+                pubsub.attributes.get(attribute)
+            })
+            .and_then(|id| store.find_twist_thread(id.clone()));
+        routed.or_else(|| store.find_twist_thread(url_id.clone()))
+    };
+    let twist = match twist {
+        Some(twist) => twist,
+        None => {
+            tide::log::warn!("no twist integration found with id {}", url_id, {
+                install_id: url_id,
+                event: "unknown_integration"
+            });
+            let mut res = tide::Response::new(StatusCode::NotFound);
+            res.set_body("no such integration");
+            return Ok(res);
+        }
+    };
+    let webhook_id = twist.secret_id.as_str();
+
+    if twist.muted {
+        tide::log::info!("skipped alert for {}, muted", webhook_id, {
+            install_id: webhook_id,
+            event: "muted"
+        });
+        return Ok("OK".into());
+    }
+    if let Some(until) = twist.mute_until.filter(|until| unix_now() < *until) {
+        tide::log::info!(
+            "skipped alert for {}, muted until {}",
+            webhook_id,
+            format_utc(until)
+        );
+        return Ok("OK".into());
+    }
+
+    // a pub/sub redelivery carries the messageId of the original, which pins
+    // down repeats better than anything in the alerts themselves
+    let message_id = replies
+        .iter()
+        .find_map(|reply| reply.pubsub.as_ref()?.message_id.clone());
+    if let Some(message_id) = &message_id {
+        let key = format!("{}/pubsub:{}", webhook_id, message_id);
+        if !state.dedup.lock().unwrap().admit(&key, unix_now()) {
+            tide::log::info!("suppressed redelivered message for {}", webhook_id, {
+                install_id: webhook_id,
+                event: "redelivery_suppressed"
+            });
+            return Ok("OK".into());
+        }
+    }
+
+    // alerts are deduplicated one by one before a batch is merged, so that a
+    // repeat doesn't hold back the new alerts delivered alongside it
+    let mut fresh = Vec::new();
+    for reply in replies {
+        if let Some(key) = admit_alert(&state, &twist, &reply, message_id.is_none()) {
+            fresh.push((reply, key));
+        }
+    }
+    let batches = batch_alerts(fresh, &twist, &state.render);
+    let mut failed = 0;
+    for (reply, keys) in &batches {
+        if !deliver_alert(&state, &twist, reply).await {
+            failed += 1;
+            let mut dedup = state.dedup.lock().unwrap();
+            for key in keys {
+                dedup.forget(key);
+            }
+        }
+    }
+    if failed > 0 {
+        if let Some(message_id) = &message_id {
+            let key = format!("{}/pubsub:{}", webhook_id, message_id);
+            state.dedup.lock().unwrap().forget(&key);
+        }
+        let mut res = tide::Response::new(StatusCode::BadGateway);
+        res.set_body(format!(
+            "{} of {} alerts not delivered to twist",
+            failed,
+            batches.len()
+        ));
+        return Ok(res);
+    }
+
+    Ok("OK".into())
+}
+
+/// Lets an alert through unless `dedup` is set and it repeats a recent one,
+/// returning the key it was recorded under, if any, to be forgotten should
+/// the post fail.
+fn admit_alert(
+    state: &State,
+    twist: &TwistIntegration,
+    reply: &RenderedAlert,
+    dedup: bool,
+) -> Option<Option<String>> {
+    let webhook_id = twist.secret_id.as_str();
+
+    let key = reply
+        .payload
+        .as_ref()
+        .filter(|_| dedup)
+        .map(|payload| format!("{}/{}", webhook_id, payload.dedup_key(&twist.fingerprint)));
+    if let Some(key) = &key {
+        if !state.dedup.lock().unwrap().admit(key, unix_now()) {
+            tide::log::info!("suppressed repeated alert for {}", webhook_id, {
+                install_id: webhook_id,
+                incident_url: reply.payload.as_ref().and_then(|p| p.incident_url()).unwrap_or_default(),
+                event: "duplicate_suppressed"
+            });
+            return None;
+        }
+    }
+    Some(key)
+}
+
+/// Posts one rendered alert to the integration's thread. Returns false when
+/// twist did not take it; alerts held back on purpose count as handled.
+async fn deliver_alert(state: &State, twist: &TwistIntegration, reply: &RenderedAlert) -> bool {
+    let webhook_id = twist.secret_id.as_str();
+
+    if unix_now() < state.quiet_until {
+        let count = state
+            .quiet_suppressed
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1;
+        tide::log::info!(
+            "held back alert for {} in the startup quiet period, {} so far",
+            webhook_id,
+            count
+        );
+        return true;
+    }
+
+    let now = unix_now();
+    let maintenance = state
+        .maintenance
+        .lock()
+        .unwrap()
+        .iter()
+        .chain(twist.maintenance_windows.iter())
+        .filter(|window| window.contains(now))
+        .fold(None, |found: Option<bool>, window| {
+            Some(found.unwrap_or(false) || window.suppress_notify_all)
+        });
+    let chart = reply
+        .payload
+        .as_ref()
+        .and_then(|p| p.chart_url())
+        .filter(|_| twist.include_chart)
+        .filter(|url| {
+            let allowed = is_allowed_chart_url(url, &state.opts.chart_host);
+            if !allowed {
+                tide::log::warn!("ignoring chart from untrusted host: {}", redact_url(url));
+            }
+            allowed
+        });
+    let checklist = twist.task_checklist && reply.payload.as_ref().is_some_and(|p| p.is_open());
+    let footer = twist
+        .footer
+        .as_ref()
+        .or(state.render.footer.as_ref())
+        .filter(|footer| !footer.is_empty())
+        .zip(reply.payload.as_ref())
+        .map(|(template, payload)| render_footer(template, payload, twist, &state.render));
+
+    let decorate = |content: &str| {
+        let content = redact(
+            content,
+            state
+                .render
+                .redactions
+                .iter()
+                .chain(twist.redact_patterns.iter().map(|pattern| &pattern.0)),
+        );
+        let content = match maintenance {
+            Some(_) => format!("🛠️ (maintenance) {}", content),
+            None => content,
+        };
+        let content = match chart {
+            Some(url) => format!("{}\n\n![metric chart]({})", content, url),
+            None => content,
+        };
+        let content = if checklist {
+            format!("{}\n\n{}", content, TASK_CHECKLIST)
+        } else {
+            content
+        };
+        let content = match &footer {
+            // shorten the alert rather than lose its links to truncation
+            Some(footer) if twist.oversize_behavior == OversizeBehavior::Truncate => {
+                let budget = state
+                    .render
+                    .content_limit
+                    .saturating_sub(footer.chars().count() + 2);
+                format!("{}\n\n{}", truncate_chars(&content, budget), footer)
+            }
+            Some(footer) => format!("{}\n\n{}", content, footer),
+            None => content,
+        };
+        with_instance_label(content, &state.opts.instance_label)
+    };
+
+    let limit = state.render.content_limit;
+    // what is added around the alert would cut off the end of long log alert
+    // documentation, and its link with it, so render that shorter instead
+    let overhead = decorate("").chars().count();
+    let content = match reply.payload.as_ref() {
+        Some(payload)
+            if twist.oversize_behavior == OversizeBehavior::Truncate
+                && reply.content.chars().count() + overhead > limit
+                && render_payload(payload, &state.render) == reply.content =>
+        {
+            decorate(&render_payload_within(
+                payload,
+                &state.render,
+                limit.saturating_sub(overhead),
+            ))
+        }
+        _ => decorate(&reply.content),
+    };
+
+    let everyone = twist.notify_all_on_open
+        && reply.payload.as_ref().is_some_and(|p| p.is_open())
+        && maintenance != Some(true);
+    let parts = match twist.oversize_behavior {
+        _ if content.chars().count() <= limit => vec![content],
+        OversizeBehavior::Truncate => vec![truncate_chars(&content, limit)],
+        OversizeBehavior::Split => split_chars(&content, limit),
+        OversizeBehavior::Drop => {
+            tide::log::warn!(
+                "dropped alert for {}, {} characters is over the limit of {}",
+                webhook_id,
+                content.chars().count(),
+                limit
+            );
+            return true;
+        }
+    };
+
+    let incident_url = reply
+        .payload
+        .as_ref()
+        .and_then(|p| p.incident_url())
+        .unwrap_or_default();
+    let post_url = twist.post_url_for(
+        reply.payload.as_ref().and_then(|p| p.severity()),
+        reply.payload.as_ref().map(|p| p.policy_name()),
+    );
+    if let Err(err) = validate_target_url(post_url, &state.opts.allow_target_cidrs).await {
+        tide::log::warn!("not forwarding alert for {}: {}", webhook_id, err, {
+            install_id: webhook_id,
+            incident_url: incident_url,
+            event: "target_rejected"
+        });
+        return false;
+    }
+    let mut delivered_all = true;
+    for (idx, part) in parts.into_iter().enumerate() {
+        let body = state
+            .opts
+            .twist_body_version
+            .body(part, everyone && idx == 0);
+        let outcome = post_to_twist(state, twist, post_url, &body).await;
+        let delivered = matches!(outcome, Ok(status) if is_success(status));
+        delivered_all &= delivered;
+        if let Some(path) = state.opts.deadletter_file.as_ref().filter(|_| !delivered) {
+            let letter = DeadLetter {
+                timestamp: unix_now(),
+                install_id: twist.secret_id.clone(),
+                severity: reply
+                    .payload
+                    .as_ref()
+                    .and_then(|p| p.severity())
+                    .map(str::to_string),
+                policy_name: reply.payload.as_ref().map(|p| p.policy_name().to_string()),
+                body: body.clone(),
+            };
+            if let Err(err) = append_json_line(path, &letter) {
+                tide::log::warn!("failed to write dead letter to {}: {}", path, err);
+            }
+        }
+        state
+            .stats
+            .lock()
+            .unwrap()
+            .record(&twist.configuration.install_id, delivered, unix_now());
+        Metrics::inc(if delivered {
+            &state.metrics.forwards_succeeded
+        } else {
+            &state.metrics.forwards_failed
+        });
+        match &outcome {
+            Ok(status) if is_success(*status) => {}
+            Ok(status) => {
+                tide::log::warn!("twist answered {} to alert for {}", status, webhook_id, {
+                    install_id: webhook_id,
+                    incident_url: incident_url,
+                    status: status,
+                    event: "forward_failed"
+                })
+            }
+            Err(err) => tide::log::warn!("failed to forward alert for {}: {}", webhook_id, err, {
+                install_id: webhook_id,
+                incident_url: incident_url,
+                event: "forward_failed"
+            }),
+        }
+
+        if let Some(path) = &state.opts.receipts_log {
+            let receipt = Receipt {
+                timestamp: unix_now(),
+                webhook_id: webhook_id.to_string(),
+                install_id: Some(twist.configuration.install_id.clone()),
+                incident_url: reply
+                    .payload
+                    .as_ref()
+                    .and_then(|p| p.incident_url())
+                    .map(str::to_string),
+                target: redact_url(post_url),
+                outcome: match &outcome {
+                    Ok(status) if is_success(*status) => "success",
+                    _ => "failure",
+                }
+                .to_string(),
+                status: outcome.as_ref().ok().copied(),
+                error: outcome.as_ref().err().cloned(),
+            };
+            if let Err(err) = append_json_line(path, &receipt) {
+                tide::log::warn!("failed to write receipt to {}: {}", path, err);
+            }
+        }
+    }
+    delivered_all
+}
+
+type StoreGuard<'a> = async_std::sync::RwLockReadGuard<'a, Box<dyn ApplicationStore>>;
+
+/// Read locks the store, or gives up with `None` once `--store-lock-timeout`
+/// has passed so a long write can't stall handlers indefinitely.
+async fn lock_store(state: &State) -> Option<StoreGuard<'_>> {
+    match state.opts.store_lock_timeout {
+        Some(ms) => {
+            async_std::future::timeout(std::time::Duration::from_millis(ms), state.store.read())
+                .await
+                .ok()
+        }
+        None => Some(state.store.read().await),
+    }
+}
+
+/// Shape of the json bodies posted to, and answered to, twist.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TwistBodyVersion {
+    /// `{"content": ..., "recipients": ...}`
+    V1,
+}
+
+impl std::str::FromStr for TwistBodyVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(TwistBodyVersion::V1),
+            _ => Err(format!("unknown twist body version {:?}, expected v1", s)),
+        }
+    }
+}
+
+impl TwistBodyVersion {
+    /// A message of `content`, notifying everyone in the thread if `everyone`.
+    fn body(self, content: String, everyone: bool) -> serde_json::Value {
+        match self {
+            TwistBodyVersion::V1 => {
+                let mut body = json!({
+                    "content": content,
+                });
+                if everyone {
+                    body["recipients"] = json!("EVERYONE_IN_THREAD");
+                }
+                body
+            }
+        }
+    }
+}
+
+/// Checks that a thread url is https and that none of the addresses its host
+/// resolves to are on the bridge's own network, unless they are `allowed`,
+/// so installs can't have the bridge post to internal services.
+async fn validate_target_url(url: &str, allowed: &[Cidr]) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|err| format!("invalid url: {}", err))?;
+    check_target_url(&parsed, allowed)?;
+    let host = parsed.host_str().unwrap_or_default();
+    resolve_target(host, parsed.port_or_known_default().unwrap_or(443), allowed).await?;
+    Ok(())
+}
+
+/// The checks of `validate_target_url` that need no lookup: the scheme, and
+/// the host when it is an address.
+fn check_target_url(url: &reqwest::Url, allowed: &[Cidr]) -> Result<(), String> {
+    if url.scheme() != "https" {
+        return Err("thread url must be https".into());
+    }
+    let host = url.host_str().ok_or("thread url has no host")?;
+    match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<std::net::IpAddr>()
+    {
+        Ok(ip) if !is_allowed_target(ip, allowed) => Err(format!("{} is an internal address", ip)),
+        _ => Ok(()),
+    }
+}
+
+/// The addresses of `host`, unless any of them is internal and not `allowed`.
+async fn resolve_target(
+    host: &str,
+    port: u16,
+    allowed: &[Cidr],
+) -> Result<Vec<std::net::SocketAddr>, String> {
+    use async_std::net::ToSocketAddrs;
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<std::net::SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .await
+        .map_err(|err| format!("failed to resolve {}: {}", host, err))?
+        .collect();
+    match addrs
+        .iter()
+        .find(|addr| !is_allowed_target(addr.ip(), allowed))
+    {
+        Some(addr) => Err(format!(
+            "{} resolves to internal address {}",
+            host,
+            addr.ip()
+        )),
+        None => Ok(addrs),
+    }
+}
+
+fn is_allowed_target(ip: std::net::IpAddr, allowed: &[Cidr]) -> bool {
+    !is_internal_ip(ip) || allowed.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// Resolves the hosts of thread posts, refusing those with internal
+/// addresses. `validate_target_url` looks the host up before the post, but
+/// it could point elsewhere by the time the client connects, so the
+/// addresses connected to are the ones checked here.
+struct TargetResolver {
+    allowed: Vec<Cidr>,
+}
+
+impl reqwest::dns::Resolve for TargetResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let allowed = self.allowed.clone();
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            // the client puts the port of the url in place of this one
+            let addrs = resolve_target(&host, 0, &allowed).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(
+                Box::new(addrs.into_iter()) as reqwest::dns::Addrs
+            )
+        })
+    }
+}
+
+/// Follows redirects, with `--follow-redirects`, only to urls that pass
+/// `check_target_url`; hosts they name are checked by `TargetResolver`.
+fn target_redirect_policy(follow: bool, allowed: Vec<Cidr>) -> reqwest::redirect::Policy {
+    if !follow {
+        return reqwest::redirect::Policy::none();
+    }
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= 10 {
+            return attempt.error("too many redirects");
+        }
+        match check_target_url(attempt.url(), &allowed) {
+            Ok(()) => attempt.follow(),
+            Err(err) => attempt.error(err),
+        }
+    })
+}
+
+fn is_internal_ip(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        std::net::IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || first & 0xfe00 == 0xfc00 // unique local
+                || first & 0xffc0 == 0xfe80 // link local
+                || ip.to_ipv4_mapped().is_some_and(|ip| is_internal_ip(ip.into()))
+        }
+    }
+}
+
+/// Signs `content` with the `--instance-label`, if there is one.
+fn with_instance_label(content: String, label: &str) -> String {
+    if label.is_empty() {
+        content
+    } else {
+        format!("{}\n\n— via {}", content, label)
+    }
+}
+
+/// Cuts `text` into parts of at most `max` characters, between lines where
+/// possible.
+fn split_chars(text: &str, max: usize) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut len = 0;
+    for line in text.split_inclusive('\n') {
+        if len > 0 && len + line.chars().count() > max {
+            parts.push(String::new());
+            len = 0;
+        }
+        for c in line.chars() {
+            if len == max {
+                parts.push(String::new());
+                len = 0;
+            }
+            parts.last_mut().unwrap().push(c);
+            len += 1;
+        }
+    }
+    parts
+}
+
+/// What to do with a message longer than `--content-limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OversizeBehavior {
+    #[default]
+    Truncate,
+    /// post it in several parts
+    Split,
+    /// post nothing, for threads where a partial alert is worse than none
+    Drop,
+}
+
+/// Triage steps appended to open incidents of integrations with
+/// `task_checklist` set.
+const TASK_CHECKLIST: &str = "- [ ] Investigate\n- [ ] Mitigate\n- [ ] Resolve";
+
+/// Hosts charts are accepted from when no `--chart-host` is given.
+const DEFAULT_CHART_HOSTS: [&str; 2] = ["storage.googleapis.com", "monitoring.googleapis.com"];
+
+fn is_allowed_chart_url(url: &str, hosts: &[String]) -> bool {
+    let parsed = match reqwest::Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "https" => parsed,
+        _ => return false,
+    };
+    let host = parsed.host_str().unwrap_or_default();
+    if hosts.is_empty() {
+        DEFAULT_CHART_HOSTS.contains(&host)
+    } else {
+        hosts.iter().any(|allowed| allowed == host)
+    }
+}
+
+fn is_success(status: u16) -> bool {
+    (200..300).contains(&status)
+}
+
+/// Delivers json posts to Twist threads. Handlers go through this rather than
+/// an http client so the forwarding path can run without the network.
+trait OutboundSender: Send + Sync {
+    /// Posts `body` to `url` once, returning the response status.
+    fn post<'a>(
+        &'a self,
+        url: &'a str,
+        auth_token: Option<&'a Secret>,
+        body: &'a serde_json::Value,
+    ) -> PostFuture<'a>;
+}
+
+type PostFuture<'a> =
+    std::pin::Pin<Box<dyn std::future::Future<Output = Result<u16, String>> + Send + 'a>>;
+
+struct ReqwestSender {
+    /// shared by every post, a redirect is answered as is, and so counts as a
+    /// failed post, unless built with --follow-redirects
+    client: reqwest::Client,
+    /// internal networks thread urls may nevertheless point into
+    allowed: Vec<Cidr>,
+}
+
+impl ReqwestSender {
+    /// Posts to thread urls, which installs chose, held to the checks of
+    /// `validate_target_url` as they connect and on every followed redirect,
+    /// each post abandoned after `timeout_secs`.
+    fn new(follow_redirects: bool, allowed: &[Cidr], timeout_secs: u64) -> reqwest::Result<Self> {
+        let timeout = std::time::Duration::from_secs(timeout_secs.max(1));
+        let client = reqwest::Client::builder()
+            .redirect(target_redirect_policy(follow_redirects, allowed.to_vec()))
+            .dns_resolver(std::sync::Arc::new(TargetResolver {
+                allowed: allowed.to_vec(),
+            }))
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .build()?;
+        Ok(Self {
+            client,
+            allowed: allowed.to_vec(),
+        })
+    }
+}
+
+impl OutboundSender for ReqwestSender {
+    fn post<'a>(
+        &'a self,
+        url: &'a str,
+        auth_token: Option<&'a Secret>,
+        body: &'a serde_json::Value,
+    ) -> PostFuture<'a> {
+        Box::pin(async move {
+            // hosts given as an address are never resolved, so are checked here
+            let parsed = reqwest::Url::parse(url).map_err(|err| format!("invalid url: {}", err))?;
+            check_target_url(&parsed, &self.allowed)?;
+            let mut request = self
+                .client
+                .request(reqwest::Method::POST, url)
+                .body(body.to_string())
+                .header("Content-Type", "application/json");
+            if let Some(token) = auth_token {
+                request = request.bearer_auth(&token.0);
+            }
+            let res = request.send().await.map_err(|err| {
+                if err.is_timeout() {
+                    format!("timed out: {}", err)
+                } else {
+                    err.to_string()
+                }
+            })?;
+            if res.status().is_redirection() {
+                let location = res
+                    .headers()
+                    .get("Location")
+                    .and_then(|location| location.to_str().ok())
+                    .unwrap_or("nowhere");
+                tide::log::warn!(
+                    "not following redirect from {} to {}",
+                    redact_url(url),
+                    redact_url(location)
+                );
+            }
+            Ok(res.status().as_u16())
+        })
+    }
+}
+
+/// Logs and records posts instead of sending them, answering each with 200.
+#[derive(Default)]
+struct RecordingSender {
+    posts: std::sync::Mutex<Vec<(String, serde_json::Value)>>,
+    /// statuses answered, in order, before falling back to 200
+    answers: std::sync::Mutex<std::collections::VecDeque<u16>>,
+}
+
+impl OutboundSender for RecordingSender {
+    fn post<'a>(
+        &'a self,
+        url: &'a str,
+        _auth_token: Option<&'a Secret>,
+        body: &'a serde_json::Value,
+    ) -> PostFuture<'a> {
+        let mut posts = self.posts.lock().unwrap();
+        posts.push((redact_url(url), body.clone()));
+        tide::log::info!(
+            "dry run post #{} to {}: {}",
+            posts.len(),
+            redact_url(url),
+            body
+        );
+        let status = self.answers.lock().unwrap().pop_front().unwrap_or(200);
+        Box::pin(async move { Ok(status) })
+    }
+}
+
+/// How a single post to twist went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Delivery {
+    Delivered,
+    /// network errors, 429 and 5xx, which may well go through later
+    Retryable,
+    /// 3xx, see `--follow-redirects`
+    Redirected,
+    /// any other 4xx, which won't go differently a second time
+    Rejected,
+}
+
+impl Delivery {
+    fn classify(outcome: &Result<u16, String>) -> Self {
+        match *outcome {
+            Ok(status) if is_success(status) => Delivery::Delivered,
+            Ok(status) if status == 429 || status >= 500 => Delivery::Retryable,
+            Ok(status) if (300..400).contains(&status) => Delivery::Redirected,
+            Ok(_) => Delivery::Rejected,
+            Err(_) => Delivery::Retryable,
+        }
+    }
+}
+
+/// Posts `body` to an integration's thread, retrying `Delivery::Retryable`
+/// attempts with jittered exponential backoff up to `--max-retries` times, and
+/// giving up early once the next backoff would overrun `--max-retry-duration`.
+async fn post_to_twist(
+    state: &State,
+    twist: &TwistIntegration,
+    url: &str,
+    body: &serde_json::Value,
+) -> Result<u16, String> {
+    let opts = &state.opts;
+    let started = std::time::Instant::now();
+    let budget = opts.max_retry_duration.map(std::time::Duration::from_secs);
+
+    let mut attempt = 0;
+    let host = if opts.latency_by_host {
+        reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+    loop {
+        let posted = std::time::Instant::now();
+        let outcome = state
+            .sender
+            .post(url, twist.auth_token.as_ref(), body)
+            .await;
+        state.metrics.observe_post(
+            match &outcome {
+                Ok(status) if is_success(*status) => "success",
+                _ => "failure",
+            },
+            &host,
+            posted.elapsed(),
+        );
+        let delivery = Delivery::classify(&outcome);
+        tide::log::debug!(
+            "post for {} attempt {}: {:?} ({:?})",
+            twist.configuration.install_id,
+            attempt + 1,
+            delivery,
+            outcome
+        );
+        if delivery != Delivery::Retryable || attempt >= opts.max_retries {
+            return outcome;
+        }
+
+        let delay = jitter(std::time::Duration::from_millis(
+            opts.retry_base_ms.saturating_mul(1 << attempt.min(16)),
+        ));
+        if let Some(budget) = budget {
+            if started.elapsed() + delay > budget {
+                tide::log::warn!(
+                    "retry budget of {:?} exhausted for {} after {} attempts",
+                    budget,
+                    twist.secret_id,
+                    attempt + 1
+                );
+                return outcome;
+            }
+        }
+        tide::log::warn!(
+            "retrying post for {} in {:?} after attempt {}: {}",
+            twist.configuration.install_id,
+            delay,
+            attempt + 1,
+            match &outcome {
+                Ok(status) => format!("twist answered {}", status),
+                Err(err) => err.clone(),
+            }
+        );
+        async_std::task::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Somewhere between half and all of `delay`, so that posts failing together
+/// are not all retried at the same moment.
+fn jitter(delay: std::time::Duration) -> std::time::Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    delay / 2 + delay.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+/// One line of the `--receipts-log`, recorded for every forward attempt.
+#[derive(Debug, Serialize, Deserialize)]
+struct Receipt {
+    timestamp: u64,
+    webhook_id: String,
+    /// missing from receipts written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    install_id: Option<String>,
+    incident_url: Option<String>,
+    target: String,
+    outcome: String,
+    status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// One line of the `--deadletter-file`: a post twist did not take after all
+/// retries, kept for `replay --deadletter-file`.
+#[derive(Debug, Serialize, Deserialize)]
+struct DeadLetter {
+    timestamp: u64,
+    install_id: String,
+    /// pick the route again on replay
+    #[serde(default)]
+    severity: Option<String>,
+    #[serde(default)]
+    policy_name: Option<String>,
+    body: serde_json::Value,
+}
+
+fn append_json_line<T: Serialize>(path: &str, entry: &T) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+/// Drops the query string of a url, which for Twist carries the install token.
+fn redact_url(url: &str) -> String {
+    url.split('?').next().unwrap_or(url).to_string()
+}
+
+async fn twist_outgoing(mut req: Request<State>) -> tide::Result {
+    #[derive(Debug, Deserialize)]
+    struct Outgoing {
+        event_type: String, // message, thread, comment, uninstall, ping
+        user_id: String,
+        user_name: String,
+
+        // only on message, thread or comment
+        content: Option<String>,
+
+        // only when event_type = uninstall
+        install_id: Option<String>,
+
+        verify_token: Option<Secret>,
+    }
+
+    if let Some(res) = media_type_rejection(&req, &req.state().opts.twist_content_type) {
+        return Ok(res);
+    }
+    let x: Outgoing = req.body_json().await?;
+    let store = &req.state().store;
+
+    // pings arrive while twist verifies an install, before it is registered
+    let registered = match x.install_id.clone().filter(|_| x.event_type != "ping") {
+        Some(install_id) => store.read().await.find_twist_thread(install_id),
+        None => None,
+    };
+    let expected = registered.and_then(|twist| twist.verify_token);
+    if let Some(expected) = expected {
+        let provided = x.verify_token.as_ref().map_or("", |token| token.0.as_str());
+        if !constant_time_eq(provided.as_bytes(), expected.0.as_bytes()) {
+            tide::log::warn!("rejecting {} event with a bad verify token", x.event_type);
+            return Ok(tide::Response::new(StatusCode::Unauthorized));
+        }
+    }
+
+    let body_version = req.state().opts.twist_body_version;
+    Ok(match x.event_type.as_str() {
+        "ping" => {
+            let mut res = tide::Response::new(StatusCode::Ok);
+            res.body_json(&body_version.body("pong".into(), false))?;
+            res
+        }
+        "message" => {
+            let mut res = tide::Response::new(200);
+            res.body_json(&body_version.body(req.state().opts.message_reply.clone(), false))?;
+            res
+        }
+        // nothing to act on, but twist expects a reply to these too
+        "thread" | "comment" => {
+            tide::log::info!(
+                "{} event from {}: {}",
+                x.event_type,
+                x.user_name,
+                truncate_chars(x.content.as_deref().unwrap_or_default(), 200)
+            );
+            let mut res = tide::Response::new(StatusCode::Ok);
+            res.body_json(&body_version.body(String::new(), false))?;
+            res
+        }
+        "uninstall" => match x.install_id.clone() {
+            Some(install_id) => {
+                if uninstall(&mut **store.write().await, install_id) {
+                    Metrics::inc(&req.state().metrics.integrations_unregistered);
+                }
+                let mut res = tide::Response::new(200);
+                res.body_json(&body_version.body("uninstalled!".into(), false))?;
+                res
+            }
+            None => {
+                tide::log::warn!("uninstall event from {} missing install_id", x.user_id);
+                let mut res = tide::Response::new(400);
+                res.set_body("uninstall event missing install_id");
+                res
+            }
+        },
+        _ => tide::Response::new(400),
+    })
+}
+
+/// Compares secrets in time that depends only on their lengths, so that a
+/// token can't be guessed byte by byte from how quickly it is rejected.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Whether `id` is fit to key the store and to appear in the webhook url.
+fn is_valid_install_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Handles Twist telling us an integration was removed from its thread.
+fn uninstall(store: &mut dyn ApplicationStore, install_id: String) -> bool {
+    let removed = store.unregister_twist_thread(install_id.clone());
+    if removed {
+        tide::log::info!("uninstalled {}", install_id);
+    } else {
+        tide::log::warn!("uninstall for unknown integration {}", install_id);
+    }
+    removed
+}
+
+async fn twist_configure(req: Request<State>) -> tide::Result {
+    if let Some(res) = standby_rejection(&req) {
+        return Ok(res);
+    }
+    let _busy = match BusySlot::acquire(req.state()) {
+        Some(busy) => busy,
+        None => return Ok(tide::Response::new(StatusCode::ServiceUnavailable)),
+    };
+
+    let x: TwistOnConfigure = req.query()?;
+    let state = req.state();
+
+    if !is_valid_install_id(&x.install_id) {
+        tide::log::warn!("rejected configure with install id {:?}", x.install_id);
+        let mut res = tide::Response::new(StatusCode::BadRequest);
+        res.set_body("Twist configuration failed: invalid install_id.");
+        return Ok(res);
+    }
+
+    if let Err(err) = validate_target_url(&x.post_data_url, &state.opts.allow_target_cidrs).await {
+        tide::log::warn!("rejected configure for {}: {}", x.install_id, err);
+        let mut res = tide::Response::new(StatusCode::BadRequest);
+        res.set_body(format!("Twist configuration failed: {}.", err));
+        return Ok(res);
+    }
+
+    let registered = {
+        let mut k = state.store.write().await;
+        let shared: Vec<String> = k
+            .list_twist_threads()
+            .into_iter()
+            .filter(|t| t.configuration.post_data_url == x.post_data_url)
+            .filter(|t| t.secret_id != x.install_id)
+            .map(|t| t.secret_id)
+            .collect();
+        if state.opts.configure_dedup == ConfigureDedup::Keep && !shared.is_empty() {
+            // intentional when the integrations filter differently, but often
+            // an accidental second install double posting every alert
+            tide::log::warn!(
+                "{} shares {} with {}",
+                x.install_id,
+                redact_url(&x.post_data_url),
+                shared.join(", ")
+            );
+            if state.opts.reject_duplicate_urls {
+                let mut res = tide::Response::new(StatusCode::Conflict);
+                res.set_body(format!(
+                    "Twist configuration failed: this thread is already served by {}.",
+                    shared.join(", ")
+                ));
+                return Ok(res);
+            }
+        }
+        if state.opts.configure_dedup == ConfigureDedup::Replace {
+            for stale in k
+                .list_twist_threads()
+                .into_iter()
+                .filter(|t| t.configuration.post_data_url == x.post_data_url)
+                .filter(|t| t.secret_id != x.install_id)
+            {
+                tide::log::info!(
+                    "{} supersedes {} on {}",
+                    x.install_id,
+                    stale.secret_id,
+                    redact_url(&x.post_data_url)
+                );
+                k.unregister_twist_thread(stale.secret_id);
+            }
+        }
+        k.register_twist_thread(x.clone())
+    };
+    match registered {
+        Ok(true) => Metrics::inc(&state.metrics.integrations_registered),
+        Ok(false) => {}
+        Err(err) => {
+            tide::log::warn!("rejected configure for {}: {}", x.install_id, err);
+            let mut res = tide::Response::new(StatusCode::InsufficientStorage);
+            res.set_body(format!("Twist configuration failed: {}.", err));
+            return Ok(res);
+        }
+    }
+
+    tide::log::info!(
+        "configure for {} on {}",
+        x.user_name,
+        redact_url(&x.post_data_url)
+    );
+
+    if !state.opts.no_install_message {
+        let hello = state.opts.twist_body_version.body(
+            with_instance_label(
+                state.opts.install_message.clone(),
+                &state.opts.instance_label,
+            ),
+            false,
+        );
+        if let Err(err) = state.sender.post(&x.post_data_url, None, &hello).await {
+            tide::log::warn!("failed to say hello to {}: {}", x.install_id, err);
+        }
+    }
+
+    let gcp_url = format!(
+        "https://{}/gcp/webhooks/{}",
+        state.server_name, x.install_id
+    );
+
+    let hello_note = if state.opts.no_install_message {
+        ""
+    } else {
+        "A hello message has been sent to your thread and will appear within 2 hours.\n\n"
+    };
+    Ok(format!(
+        "
+Twist configuration successful.
+
+# GCP Notification Channel
+Webhook URL: {}
+
+{}GCP Notifications will be relayed in hourly batches.
+",
+        gcp_url, hello_note
+    )
+    .into())
+}
+
+/// Returns a 415 to send instead of reading the body, if the request declares a
+/// content type that is not in `allowed`, or not json when that is empty.
+/// Requests without a content type are let through.
+fn media_type_rejection<S>(req: &Request<S>, allowed: &[String]) -> Option<tide::Response> {
+    let content_type = req.content_type()?;
+    let essence = content_type.essence();
+    let accepted = if allowed.is_empty() {
+        essence == "application/json"
+    } else {
+        allowed
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(essence))
+    };
+    if accepted {
+        None
+    } else {
+        tide::log::warn!("rejecting request with content type {}", essence);
+        Some(tide::Response::new(StatusCode::UnsupportedMediaType))
+    }
+}
+
+/// Returns the response to send instead of handling an admin request, if the
+/// request does not carry the configured admin token.
+fn admin_rejection(req: &Request<State>) -> Option<tide::Response> {
+    let expected = match &req.state().opts.admin_token {
+        Some(token) => token,
+        None => return Some(tide::Response::new(StatusCode::NotFound)),
+    };
+    let provided = req
+        .header("Authorization")
+        .and_then(|values| values.as_str().strip_prefix("Bearer "));
+    if provided.is_some_and(|provided| constant_time_eq(provided.as_bytes(), expected.as_bytes())) {
+        None
+    } else {
+        Some(tide::Response::new(StatusCode::Unauthorized))
+    }
+}
+
+/// Per-integration settings that are only changeable through the admin api,
+/// so that secrets never travel in query strings. Absent fields are left as is.
+#[derive(Deserialize)]
+struct IntegrationSettings {
+    /// bearer token sent along forwarded alerts, an empty string clears it
+    auth_token: Option<String>,
+    /// notify everyone in the thread when an uptime incident opens
+    notify_all_on_open: Option<bool>,
+    /// thread urls keyed by severity, replacing the current routes
+    severity_routes: Option<std::collections::HashMap<String, String>>,
+    /// thread urls by policy name regex, replacing the current routes
+    policy_routes: Option<Vec<PolicyRoute>>,
+    /// redaction regexes, replacing the current ones
+    redact_patterns: Option<Vec<RedactPattern>>,
+    /// planned maintenance of this integration, replacing the current windows
+    maintenance_windows: Option<Vec<MaintenanceWindow>>,
+    /// embed the incident's metric chart when the payload has one
+    include_chart: Option<bool>,
+    /// post open incidents with a triage checklist
+    task_checklist: Option<bool>,
+    /// truncate, split or drop messages over the content limit
+    oversize_behavior: Option<OversizeBehavior>,
+    /// fields correlating an incident's notifications, e.g.
+    /// `["policy_name", "resource.project_id"]`, empty to use the url
+    fingerprint: Option<Vec<String>>,
+    /// footer template replacing the global one, empty for none
+    footer: Option<String>,
+    /// linked from the footer as `{runbook_url}`, empty to clear
+    runbook_url: Option<String>,
+}
+
+impl IntegrationSettings {
+    /// Checks the settings as applied to the stored integration, `merged`.
+    /// `max_targets` bounds the thread urls an alert may be posted to, the
+    /// configured post_data_url included, once routes are being set.
+    fn validate(&self, merged: &TwistIntegration, max_targets: usize) -> Result<(), String> {
+        let routing = self.severity_routes.is_some() || self.policy_routes.is_some();
+        if routing && merged.targets() > max_targets {
+            return Err(format!(
+                "{} targets exceed the limit of {} per integration",
+                merged.targets(),
+                max_targets
+            ));
+        }
+        for field in self.fingerprint.iter().flatten() {
+            let known = matches!(
+                field.as_str(),
+                "url" | "policy_name" | "severity" | "resource_type"
+            );
+            if !known && !field.starts_with("resource.") {
+                return Err(format!(
+                    "unknown fingerprint field {:?}, expected url, policy_name, severity, \
+                     resource_type or resource.<label>",
+                    field
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, twist: &mut TwistIntegration) {
+        if let Some(token) = &self.auth_token {
+            twist.auth_token = if token.is_empty() {
+                None
+            } else {
+                Some(Secret(token.clone()))
+            };
+        }
+        if let Some(notify_all) = self.notify_all_on_open {
+            twist.notify_all_on_open = notify_all;
+        }
+        if let Some(routes) = &self.policy_routes {
+            twist.policy_routes = routes.clone();
+        }
+        if let Some(routes) = &self.severity_routes {
+            twist.severity_routes = routes
+                .iter()
+                .map(|(severity, url)| (severity.to_lowercase(), url.clone()))
+                .collect();
+        }
+        if let Some(patterns) = &self.redact_patterns {
+            twist.redact_patterns = patterns.clone();
+        }
+        if let Some(windows) = &self.maintenance_windows {
+            twist.maintenance_windows = windows.clone();
+        }
+        if let Some(include_chart) = self.include_chart {
+            twist.include_chart = include_chart;
+        }
+        if let Some(task_checklist) = self.task_checklist {
+            twist.task_checklist = task_checklist;
+        }
+        if let Some(oversize) = self.oversize_behavior {
+            twist.oversize_behavior = oversize;
+        }
+        if let Some(fingerprint) = &self.fingerprint {
+            twist.fingerprint = fingerprint.clone();
+        }
+        if let Some(footer) = &self.footer {
+            twist.footer = Some(footer.clone());
+        }
+        if let Some(url) = &self.runbook_url {
+            twist.runbook_url = Some(url.clone()).filter(|url| !url.is_empty());
+        }
+    }
+}
+
+/// A planned maintenance period, alerts fired during it are labelled as such.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MaintenanceWindow {
+    /// epoch seconds
+    start: u64,
+    /// epoch seconds
+    end: u64,
+    /// also hold back notifying the whole thread during the window
+    #[serde(default)]
+    suppress_notify_all: bool,
+}
+
+impl MaintenanceWindow {
+    fn contains(&self, at: u64) -> bool {
+        self.start <= at && at < self.end
+    }
+}
+
+async fn admin_get_maintenance(req: Request<State>) -> tide::Result {
+    if let Some(res) = admin_rejection(&req) {
+        return Ok(res);
+    }
+
+    let windows = req.state().maintenance.lock().unwrap().clone();
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.body_json(&windows)?;
+    Ok(res)
+}
+
+/// Replaces the maintenance windows that apply to every integration. These
+/// are kept in memory only and reset on restart.
+async fn admin_set_maintenance(mut req: Request<State>) -> tide::Result {
+    if let Some(res) = admin_rejection(&req) {
+        return Ok(res);
+    }
+
+    let windows: Vec<MaintenanceWindow> = req.body_json().await?;
+    tide::log::info!("set {} global maintenance windows", windows.len());
+    *req.state().maintenance.lock().unwrap() = windows;
+    Ok("OK".into())
+}
+
+async fn admin_update_integration(mut req: Request<State>) -> tide::Result {
+    if let Some(res) = admin_rejection(&req).or_else(|| standby_rejection(&req)) {
+        return Ok(res);
+    }
+
+    let bad_request = |err: String| {
+        let mut res = tide::Response::new(StatusCode::BadRequest);
+        res.set_body(err);
+        res
+    };
+    // read by hand so that an invalid route or redaction regex is answered 400
+    let settings: IntegrationSettings = match serde_json::from_str(&req.body_string().await?) {
+        Ok(settings) => settings,
+        Err(err) => return Ok(bad_request(err.to_string())),
+    };
+    let install_id = req.param("id")?.to_string();
+    let state = req.state().clone();
+    // routes are posted to like the configured thread url, and held to the
+    // same checks, looked up before the store is locked
+    let routes = settings
+        .severity_routes
+        .iter()
+        .flat_map(|routes| routes.values())
+        .chain(
+            settings
+                .policy_routes
+                .iter()
+                .flatten()
+                .map(|route| &route.post_data_url),
+        );
+    for url in routes {
+        if let Err(err) = validate_target_url(url, &state.opts.allow_target_cidrs).await {
+            return Ok(bad_request(format!("route {}: {}", redact_url(url), err)));
+        }
+    }
+    let mut store = state.store.write().await;
+
+    let mut twist = match store.find_twist_thread(install_id.clone()) {
+        Some(twist) => twist,
+        None => return Ok(tide::Response::new(StatusCode::NotFound)),
+    };
+    settings.apply(&mut twist);
+    if let Err(err) = settings.validate(&twist, state.opts.max_targets_per_integration) {
+        return Ok(bad_request(err));
+    }
+    store.update_twist_thread(&install_id, &mut |stored| *stored = twist.clone());
+    tide::log::info!("updated settings for {}", install_id);
+    Ok("OK".into())
+}
+
+/// Fails when the store is unusable. An unreachable twist only degrades the
+/// bridge, it can still take alerts in, so that is reported but answered 200.
+async fn healthz(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    let checked = match lock_store(state).await {
+        Some(store) => store.check(),
+        None => Err("locked".to_string()),
+    };
+    if let Err(reason) = checked {
+        tide::log::warn!("health check failed, store {}", reason);
+        let mut res = tide::Response::new(StatusCode::ServiceUnavailable);
+        res.body_json(&json!({"status": "failed", "store": reason}))?;
+        return Ok(res);
+    }
+
+    let twist = match twist_reachable(state).await {
+        Some(true) => "reachable",
+        Some(false) => "unreachable",
+        None => "unchecked",
+    };
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.body_json(&json!({
+        "status": if twist == "unreachable" { "degraded" } else { "ok" },
+        "store": "ok",
+        "twist": twist,
+        "draining": state.draining.load(std::sync::atomic::Ordering::Relaxed),
+    }))?;
+    Ok(res)
+}
+
+async fn metrics(req: Request<State>) -> tide::Result {
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_content_type("text/plain; version=0.0.4");
+    res.set_body(req.state().metrics.render());
+    Ok(res)
+}
+
+/// Whether new requests should be routed here, which stops being the case
+/// while the bridge drains for shutdown.
+async fn ready(req: Request<State>) -> tide::Result {
+    if req
+        .state()
+        .draining
+        .load(std::sync::atomic::Ordering::Relaxed)
+    {
+        let mut res = tide::Response::new(StatusCode::ServiceUnavailable);
+        res.set_body("draining");
+        return Ok(res);
+    }
+    Ok("OK".into())
+}
+
+/// Waits for SIGTERM or SIGINT, drains and exits, see `drain_after_signal`.
+fn drain_on_shutdown(state: State) {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    let signals = match signal_hook::iterator::Signals::new([SIGTERM, SIGINT]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            tide::log::warn!("failed to listen for shutdown signals: {}", err);
+            return;
+        }
+    };
+    if drain_after_signal(&state, signals).is_some() {
+        tide::log::info!("byee!");
+        std::process::exit(0);
+    }
+}
+
+/// Waits for one of `signals`, then fails /ready for `--drain-secs` so load
+/// balancers stop routing here, refuses new work and waits up to
+/// `--shutdown-grace-secs` for the requests in flight. The listener stays
+/// open until the process exits, connections accepted meanwhile are answered
+/// 503 through `closing` rather than reset.
+fn drain_after_signal(
+    state: &State,
+    mut signals: signal_hook::iterator::Signals,
+) -> Option<std::os::raw::c_int> {
+    let signal = signals.forever().next()?;
+    tide::log::info!(
+        "received signal {}, draining for {}s",
+        signal,
+        state.opts.drain_secs
+    );
+    state
+        .draining
+        .store(true, std::sync::atomic::Ordering::Relaxed);
+    std::thread::sleep(std::time::Duration::from_secs(state.opts.drain_secs));
+    wind_down(state);
+    Some(signal)
+}
+
+/// Refuses new work, waits up to `--shutdown-grace-secs` for the requests in
+/// flight and saves what would otherwise be lost on exit.
+fn wind_down(state: &State) {
+    use std::sync::atomic::Ordering;
+
+    state.closing.store(true, Ordering::SeqCst);
+    let deadline =
+        std::time::Instant::now() + std::time::Duration::from_secs(state.opts.shutdown_grace_secs);
+    while state.busy.load(Ordering::SeqCst) > 0 && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let busy = state.busy.load(Ordering::SeqCst);
+    if busy > 0 {
+        tide::log::warn!("exiting with {} requests still in flight", busy);
+    }
+    // only written every --stats-interval otherwise, dead letters and receipts
+    // are appended and closed as each post finishes, so those waited for above
+    // are on disk already
+    state.stats.lock().unwrap().persist();
+    let opts = &state.opts;
+    if let Some(pidfile) =
+        StoreArgs::new(&opts.db, &opts.store, &opts.db_key, &opts.db_key_file).pidfile()
+    {
+        let _ = std::fs::remove_file(pidfile);
+    }
+    if let Some(path) = state.opts.bind_addr.strip_prefix("unix:") {
+        if let Err(err) = remove_socket(path) {
+            tide::log::warn!("{}", err);
+        }
+    }
+}
+
+/// Removes the unix socket at `path`, such as one left behind by a bridge that
+/// did not get to clean up. Anything else found there is left alone and an
+/// error, as it is not ours to delete.
+fn remove_socket(path: &str) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            std::fs::remove_file(path)
+                .map_err(|err| format!("failed to remove socket {}: {}", path, err))?;
+            tide::log::info!("removed socket {}", path);
+            Ok(())
+        }
+        Ok(_) => Err(format!("{} exists and is not a socket", path)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(format!("failed to inspect {}: {}", path, err)),
+    }
+}
+
+/// Whether `--health-probe-url` answered, probing it at most once every
+/// `--health-probe-interval`. `None` when there is nothing to probe.
+async fn twist_reachable(state: &State) -> Option<bool> {
+    let url = state.opts.health_probe_url.as_ref()?;
+    let now = unix_now();
+    let cached = *state.probe.lock().unwrap();
+    if let Some((checked_at, reachable)) = cached {
+        if now < checked_at + state.opts.health_probe_interval {
+            return Some(reachable);
+        }
+    }
+
+    let reachable = state
+        .http
+        .get(url)
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await
+        .is_ok_and(|res| res.status().is_success());
+    if !reachable {
+        tide::log::warn!("health probe of {} failed", redact_url(url));
+    }
+    *state.probe.lock().unwrap() = Some((now, reachable));
+    Some(reachable)
+}
+
+/// Delivery counters of one integration, for the thread's owner to check on.
+async fn twist_status(req: Request<State>) -> tide::Result {
+    let id = req.param("id")?.to_string();
+    let twist = req.state().store.read().await.find_twist_thread(id);
+    let twist = match twist {
+        Some(twist) => twist,
+        None => return Ok(tide::Response::new(StatusCode::NotFound)),
+    };
+
+    let install_id = &twist.configuration.install_id;
+    let stats = req.state().stats.lock().unwrap().get(install_id);
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.body_json(&json!({
+        "install_id": install_id,
+        "succeeded": stats.succeeded,
+        "failed": stats.failed,
+        "last_success": stats.last_success.map(format_utc),
+    }))?;
+    Ok(res)
+}
+
+async fn admin_list_integrations(req: Request<State>) -> tide::Result {
+    if let Some(res) = admin_rejection(&req) {
+        return Ok(res);
+    }
+
+    let twists = req.state().store.read().await.list_twist_threads();
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.body_json(&twists)?;
+    Ok(res)
+}
+
+/// Returns the response to send instead of handling a write on a standby,
+/// whose integrations are owned by the primary it replicates from.
+fn standby_rejection(req: &Request<State>) -> Option<tide::Response> {
+    let primary = req.state().opts.replicate_from.as_ref()?;
+    let mut res = tide::Response::new(StatusCode::ServiceUnavailable);
+    res.set_body(format!(
+        "This bridge is a standby, configure it through {}.",
+        primary
+    ));
+    Some(res)
+}
+
+/// Keeps the local store in sync with the `--replicate-from` primary.
+fn replicate(state: State) {
+    let primary = state.opts.replicate_from.clone().unwrap_or_default();
+    let interval = std::time::Duration::from_secs(state.opts.replicate_interval.max(1));
+
+    loop {
+        match fetch_integrations(&primary, state.opts.admin_token.as_deref()) {
+            Ok(twists) => {
+                let count = twists.len();
+                async_std::task::block_on(state.store.write()).replace_twist_threads(twists);
+                tide::log::debug!("replicated {} integrations from {}", count, primary);
+            }
+            Err(err) => tide::log::warn!("failed to replicate from {}: {}", primary, err),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+fn fetch_integrations(primary: &str, token: Option<&str>) -> Result<Vec<TwistIntegration>, String> {
+    let mut request = reqwest::blocking::Client::new().get(format!(
+        "{}/admin/integrations",
+        primary.trim_end_matches('/')
+    ));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    let res = request.send().map_err(|err| err.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("primary answered {}", res.status()));
+    }
+    let body = res.text().map_err(|err| err.to_string())?;
+    serde_json::from_str(&body).map_err(|err| err.to_string())
+}
+
+/// Longest an integration can be muted for at once, a year.
+const MAX_MUTE_SECS: u64 = 366 * 24 * 60 * 60;
+
+async fn admin_mute_integration(mut req: Request<State>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Mute {
+        duration_secs: u64,
+    }
+
+    if let Some(res) = admin_rejection(&req).or_else(|| standby_rejection(&req)) {
+        return Ok(res);
+    }
+
+    let mute: Mute = req.body_json().await?;
+    if mute.duration_secs > MAX_MUTE_SECS {
+        let mut res = tide::Response::new(StatusCode::BadRequest);
+        res.set_body(format!("duration_secs must be at most {}", MAX_MUTE_SECS));
+        return Ok(res);
+    }
+    let install_id = req.param("id")?.to_string();
+    let state = req.state().clone();
+    let until = unix_now() + mute.duration_secs;
+
+    let mut was_muted = false;
+    let found = state
+        .store
+        .write()
+        .await
+        .update_twist_thread(&install_id, &mut |twist| {
+            was_muted = twist.mute_until.is_some_and(|at| unix_now() < at);
+            twist.mute_until = Some(until);
+        });
+    if !found {
+        return Ok(tide::Response::new(StatusCode::NotFound));
+    }
+    tide::log::info!("muted {} until {}", install_id, format_utc(until));
+
+    let twist = state.store.read().await.find_twist_thread(install_id);
+    if !was_muted {
+        if let Some(twist) = twist {
+            let body = state.opts.twist_body_version.body(
+                with_instance_label(
+                    format!("🔇 Alerts muted until {}.", format_utc(until)),
+                    &state.opts.instance_label,
+                ),
+                false,
+            );
+            let url = twist.configuration.post_data_url.clone();
+            if let Err(err) = state
+                .sender
+                .post(&url, twist.auth_token.as_ref(), &body)
+                .await
+            {
+                tide::log::warn!(
+                    "failed to post mute notice for {}: {}",
+                    twist.secret_id,
+                    err
+                );
+            }
+        }
+    }
+
+    Ok("OK".into())
+}
+
+async fn admin_unmute_integration(req: Request<State>) -> tide::Result {
+    if let Some(res) = admin_rejection(&req).or_else(|| standby_rejection(&req)) {
+        return Ok(res);
+    }
+
+    let install_id = req.param("id")?.to_string();
+    let mut store = req.state().store.write().await;
+    if store.update_twist_thread(&install_id, &mut |twist| twist.mute_until = None) {
+        tide::log::info!("unmuted {}", install_id);
+        Ok("OK".into())
+    } else {
+        Ok(tide::Response::new(StatusCode::NotFound))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THREAD_URL: &str = "https://203.0.113.7/post_data?install_id=i1&install_token=t";
+
+    /// A path in the temp dir no other test uses.
+    fn temp_path(name: &str) -> String {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        std::env::temp_dir()
+            .join(format!("bridge-test-{}-{}-{}", std::process::id(), n, name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn configure(install_id: &str) -> TwistOnConfigure {
+        TwistOnConfigure {
+            install_id: install_id.to_string(),
+            post_data_url: THREAD_URL.to_string(),
+            user_id: "1".to_string(),
+            user_name: "tester".to_string(),
+            verify_token: None,
+        }
+    }
+
+    fn integration(install_id: &str) -> TwistIntegration {
+        TwistIntegration::new(configure(install_id))
+    }
+
+    fn serve_opts(args: &[&str]) -> BridgeCmdServe {
+        BridgeCmdServe::from_args(&["serve"], args).unwrap_or_else(|exit| panic!("{}", exit.output))
+    }
+
+    /// A bridge serving `twists`, started with `args`, posting to `sender`.
+    fn test_state(
+        args: &[&str],
+        twists: Vec<TwistIntegration>,
+        sender: std::sync::Arc<RecordingSender>,
+    ) -> State {
+        let mut store = FileStore::new(&temp_path("db.json"));
+        store.twist_integrations = twists;
+        let opts = serve_opts(args);
+        let render = RenderOptions::from_serve(&opts).unwrap();
+        State::new(
+            "bridge.example.com",
+            Box::new(store),
+            opts,
+            render,
+            sender,
+            reqwest::Client::new(),
+        )
+    }
+
+    const ADMIN_TOKEN: &str = "admin-secret";
+
+    /// Sends a json request to the routes of `serve`, as the admin.
+    async fn send(
+        state: &State,
+        method: tide::http::Method,
+        path: &str,
+        body: &str,
+    ) -> tide::http::Response {
+        let mut app = tide::with_state(state.clone());
+        app.at("/twist/on_configure").get(twist_configure);
+        app.at("/twist/outgoing").post(twist_outgoing);
+        app.at("/gcp/webhooks/:id").post(gcp_webhook);
+        app.at("/admin/integrations/:id/mute")
+            .post(admin_mute_integration);
+        app.at("/admin/integrations/:id")
+            .post(admin_update_integration);
+        app.at("/healthz").get(healthz);
+        let url = format!("http://bridge.example.com{}", path);
+        let mut req = tide::http::Request::new(method, tide::http::Url::parse(&url).unwrap());
+        req.insert_header("Authorization", format!("Bearer {}", ADMIN_TOKEN));
+        req.set_body(body);
+        req.set_content_type(tide::http::mime::JSON);
+        app.respond(req).await.unwrap()
+    }
+
+    async fn post_alert(state: &State, id: &str, body: &str) -> tide::http::Response {
+        let path = format!("/gcp/webhooks/{}", id);
+        send(state, tide::http::Method::Post, &path, body).await
+    }
+
+    fn pubsub_envelope(alert: &str, message_id: &str) -> String {
+        use base64::Engine as _;
+
+        json!({
+            "message": {
+                "data": base64::engine::general_purpose::STANDARD.encode(alert),
+                "messageId": message_id,
+            }
+        })
+        .to_string()
+    }
+
+    /// base64 of 32 zero bytes
+    const TEST_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    #[test]
+    fn subcommands_load_encrypted_stores() {
+        let path = temp_path("encrypted.json");
+        let mut file = FileStore::new(&path);
+        file.key = DbKey::from_base64(TEST_KEY).ok();
+        file.register_twist_thread(configure("i1")).unwrap();
+        assert!(!std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("install_token"));
+
+        let key = Some(TEST_KEY.to_string());
+        let store = StoreArgs::new(&path, &None, &key, &None).load();
+        let twists = store.list_twist_threads();
+        assert_eq!(twists.len(), 1);
+        assert_eq!(twists[0].configuration.post_data_url, THREAD_URL);
+
+        let entries = StoreArgs::new(&path, &None, &key, &None).entries().unwrap();
+        assert_eq!(entries[0]["configuration"]["post_data_url"], THREAD_URL);
+    }
+
+    #[test]
+    fn store_args_prefer_the_store_url() {
+        let sqlite = Some(String::from("sqlite:///var/lib/bridge.sqlite"));
+        assert_eq!(
+            StoreArgs::new("db.json", &None, &None, &None).url(),
+            "file://db.json"
+        );
+        assert_eq!(
+            StoreArgs::new("db.json", &sqlite, &None, &None).url(),
+            "sqlite:///var/lib/bridge.sqlite"
+        );
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_tokens() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cre"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+    }
+
+    #[test]
+    fn verify_tokens_stay_out_of_debug_output() {
+        let mut twist = integration("i1");
+        twist.verify_token = Some(Secret("hunter2".into()));
+        assert!(!format!("{:?}", twist).contains("hunter2"));
+    }
+
+    async fn post_outgoing(state: &State, event: serde_json::Value) -> tide::http::Response {
+        let body = event.to_string();
+        send(state, tide::http::Method::Post, "/twist/outgoing", &body).await
+    }
+
+    #[async_std::test]
+    async fn uninstall_needs_the_verify_token() {
+        let mut twist = integration("i1");
+        twist.verify_token = Some(Secret("hunter2".into()));
+        let state = test_state(&[], vec![twist], Default::default());
+        let event = |token: &str| {
+            json!({
+                "event_type": "uninstall",
+                "user_id": "1",
+                "user_name": "tester",
+                "install_id": "i1",
+                "verify_token": token,
+            })
+        };
+
+        let res = post_outgoing(&state, event("hunter3")).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert!(state
+            .store
+            .read()
+            .await
+            .find_twist_thread("i1".into())
+            .is_some());
+
+        let res = post_outgoing(&state, event("hunter2")).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(state
+            .store
+            .read()
+            .await
+            .find_twist_thread("i1".into())
+            .is_none());
+    }
+
+    fn log_alert_with_docs(docs: &str) -> String {
+        let mut alert: serde_json::Value = serde_json::from_str(SAMPLE_LOG_ALERT).unwrap();
+        alert["incident"]["documentation"]["content"] = json!(docs);
+        alert.to_string()
+    }
+
+    const DOCS_LINK: &str =
+        "[full documentation](https://console.cloud.google.com/monitoring/alerting/incidents/0.def)";
+
+    #[test]
+    fn huge_docs_keep_their_link() {
+        let reply = reply_to_json(
+            log_alert_with_docs(&"x".repeat(50_000)),
+            &RenderOptions::default(),
+        );
+        assert!(reply.content.chars().count() <= DEFAULT_CONTENT_LIMIT);
+        assert!(reply.content.ends_with(DOCS_LINK), "{}", reply.content);
+    }
+
+    #[test]
+    fn short_docs_are_left_alone() {
+        let reply = reply_to_json(
+            log_alert_with_docs("see the runbook"),
+            &RenderOptions::default(),
+        );
+        assert!(reply.content.ends_with("see the runbook"));
+        assert!(!reply.content.contains(DOCS_LINK));
+    }
+
+    #[async_std::test]
+    async fn huge_docs_keep_their_link_next_to_a_footer() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let mut twist = integration("i1");
+        twist.footer = Some(String::from(
+            "[incident]({url}) · reply `ack` to acknowledge",
+        ));
+        let state = test_state(
+            &["--content-limit", "2000", "--instance-label", "eu"],
+            vec![twist],
+            sender.clone(),
+        );
+
+        post_alert(&state, "i1", &log_alert_with_docs(&"x".repeat(50_000))).await;
+        let posts = sender.posts.lock().unwrap();
+        let content = posts[0].1["content"].as_str().unwrap();
+        assert!(content.chars().count() <= 2000);
+        assert!(content.contains(DOCS_LINK), "{}", content);
+        assert!(content.ends_with("reply `ack` to acknowledge\n\n— via eu"));
+    }
+
+    async fn update_settings(
+        state: &State,
+        id: &str,
+        settings: serde_json::Value,
+    ) -> tide::http::Response {
+        let path = format!("/admin/integrations/{}", id);
+        send(
+            state,
+            tide::http::Method::Post,
+            &path,
+            &settings.to_string(),
+        )
+        .await
+    }
+
+    fn policy_route(policy: &str, url: &str) -> PolicyRoute {
+        serde_json::from_value(json!({ "policy": policy, "post_data_url": url })).unwrap()
+    }
+
+    #[test]
+    fn policy_routes_win_over_severity_routes() {
+        let mut twist = integration("i1");
+        twist
+            .severity_routes
+            .insert("critical".into(), "https://203.0.113.8/critical".into());
+        twist.policy_routes = vec![
+            policy_route("^db-", "https://203.0.113.9/db"),
+            policy_route("db", "https://203.0.113.10/other"),
+        ];
+
+        assert_eq!(
+            twist.post_url_for(Some("Critical"), Some("db-latency")),
+            "https://203.0.113.9/db"
+        );
+        assert_eq!(
+            twist.post_url_for(Some("Critical"), Some("api errors")),
+            "https://203.0.113.8/critical"
+        );
+        assert_eq!(twist.post_url_for(None, Some("api errors")), THREAD_URL);
+    }
+
+    #[test]
+    fn invalid_policy_routes_do_not_load() {
+        let route = serde_json::from_value::<PolicyRoute>(json!({
+            "policy": "db-(",
+            "post_data_url": "https://203.0.113.9/db",
+        }));
+        assert!(route.is_err());
+    }
+
+    #[async_std::test]
+    async fn invalid_policy_routes_are_rejected() {
+        let state = test_state(
+            &["--admin-token", ADMIN_TOKEN],
+            vec![integration("i1")],
+            Default::default(),
+        );
+        let settings = json!({
+            "policy_routes": [{ "policy": "db-(", "post_data_url": "https://203.0.113.9/db" }],
+        });
+        let res = update_settings(&state, "i1", settings).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+
+    #[async_std::test]
+    async fn target_cap_counts_the_routes_already_stored() {
+        let mut twist = integration("i1");
+        twist
+            .severity_routes
+            .insert("critical".into(), "https://203.0.113.8/critical".into());
+        twist
+            .severity_routes
+            .insert("warning".into(), "https://203.0.113.8/warning".into());
+        let state = test_state(
+            &[
+                "--admin-token",
+                ADMIN_TOKEN,
+                "--max-targets-per-integration",
+                "4",
+            ],
+            vec![twist],
+            Default::default(),
+        );
+
+        let two_policies = json!({
+            "policy_routes": [
+                { "policy": "^db-", "post_data_url": "https://203.0.113.9/db" },
+                { "policy": "^api-", "post_data_url": "https://203.0.113.9/api" },
+            ],
+        });
+        let res = update_settings(&state, "i1", two_policies).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        let one_policy = json!({
+            "policy_routes": [{ "policy": "^db-", "post_data_url": "https://203.0.113.9/db" }],
+        });
+        let res = update_settings(&state, "i1", one_policy).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let stored = state
+            .store
+            .read()
+            .await
+            .find_twist_thread("i1".into())
+            .unwrap();
+        assert_eq!(stored.targets(), 4);
+    }
+
+    fn sqlite_store() -> SqliteStore {
+        let mut sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.load();
+        sqlite
+    }
+
+    #[test]
+    fn sqlite_store_round_trips_integrations() {
+        let mut sqlite = sqlite_store();
+        sqlite.register_twist_thread(configure("i1")).unwrap();
+        sqlite.register_twist_thread(configure("i2")).unwrap();
+        assert!(sqlite.update_twist_thread("i2", &mut |twist| twist.muted = true));
+        assert!(!sqlite.update_twist_thread("i3", &mut |twist| twist.muted = true));
+
+        let twists = sqlite.list_twist_threads();
+        let ids: Vec<&str> = twists.iter().map(|t| t.secret_id.as_str()).collect();
+        assert_eq!(ids, ["i1", "i2"]);
+        assert!(sqlite.find_twist_thread("i2".into()).unwrap().muted);
+        assert!(sqlite.check().is_ok());
+
+        assert!(sqlite.unregister_twist_thread("i1".into()));
+        assert!(!sqlite.unregister_twist_thread("i1".into()));
+        assert!(sqlite.find_twist_thread("i1".into()).is_none());
+    }
+
+    #[test]
+    fn sqlite_store_reconfigure_keeps_settings() {
+        let mut sqlite = sqlite_store();
+        sqlite.register_twist_thread(configure("i1")).unwrap();
+        sqlite.update_twist_thread("i1", &mut |twist| twist.include_chart = true);
+        let mut again = configure("i1");
+        again.user_name = "renamed".into();
+        sqlite.register_twist_thread(again).unwrap();
+
+        let twist = sqlite.find_twist_thread("i1".into()).unwrap();
+        assert!(twist.include_chart);
+        assert_eq!(twist.configuration.user_name, "renamed");
+        assert_eq!(sqlite.list_twist_threads().len(), 1);
+    }
+
+    #[test]
+    fn sqlite_store_enforces_max_integrations() {
+        let mut sqlite = sqlite_store();
+        sqlite.max_integrations = Some(1);
+        sqlite.register_twist_thread(configure("i1")).unwrap();
+        assert!(sqlite.register_twist_thread(configure("i2")).is_err());
+    }
+
+    #[test]
+    fn sqlite_store_encrypts_rows() {
+        let mut sqlite = sqlite_store();
+        sqlite.key = DbKey::from_base64(TEST_KEY).ok();
+        sqlite.register_twist_thread(configure("i1")).unwrap();
+
+        assert!(sqlite
+            .rows()
+            .iter()
+            .all(|row| !row.contains("install_token")));
+        assert_eq!(
+            sqlite
+                .find_twist_thread("i1".into())
+                .unwrap()
+                .configuration
+                .post_data_url,
+            THREAD_URL
+        );
+    }
+
+    #[test]
+    fn sqlite_store_replaces_all_integrations() {
+        let mut sqlite = sqlite_store();
+        sqlite.register_twist_thread(configure("i1")).unwrap();
+        sqlite.replace_twist_threads(vec![integration("i2"), integration("i3")]);
+        let twists = sqlite.list_twist_threads();
+        let ids: Vec<&str> = twists.iter().map(|t| t.secret_id.as_str()).collect();
+        assert_eq!(ids, ["i2", "i3"]);
+    }
+
+    #[test]
+    fn wind_down_persists_delivery_stats() {
+        let stats_file = temp_path("stats.json");
+        let state = test_state(
+            &[
+                "--stats-file",
+                stats_file.as_str(),
+                "--stats-interval",
+                "3600",
+            ],
+            vec![integration("i1")],
+            Default::default(),
+        );
+        state
+            .stats
+            .lock()
+            .unwrap()
+            .record("i1", true, 1_700_000_000);
+
+        wind_down(&state);
+        let persisted = DeliveryStats::load(Some(stats_file));
+        assert_eq!(persisted.get("i1").succeeded, 1);
+        assert_eq!(persisted.get("i1").last_success, Some(1_700_000_000));
+        assert!(state.closing.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn only_sockets_are_removed() {
+        let socket = temp_path("bridge.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        assert!(remove_socket(&socket).is_ok());
+        assert!(std::fs::symlink_metadata(&socket).is_err());
+        assert!(remove_socket(&socket).is_ok());
+
+        let file = temp_path("not-a.sock");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(remove_socket(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+    }
+
+    #[async_std::test]
+    async fn repeat_after_a_failed_post_is_posted() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        sender.answers.lock().unwrap().push_back(500);
+        let state = test_state(
+            &["--dedup-window-secs", "3600"],
+            vec![integration("i1")],
+            sender.clone(),
+        );
+
+        let first = post_alert(&state, "i1", SAMPLE_UPTIME_ALERT).await;
+        assert_eq!(first.status(), StatusCode::BadGateway);
+        let second = post_alert(&state, "i1", SAMPLE_UPTIME_ALERT).await;
+        assert_eq!(second.status(), StatusCode::Ok);
+        let third = post_alert(&state, "i1", SAMPLE_UPTIME_ALERT).await;
+        assert_eq!(third.status(), StatusCode::Ok);
+        assert_eq!(sender.posts.lock().unwrap().len(), 2);
+    }
+
+    #[async_std::test]
+    async fn pubsub_redelivery_after_a_failed_post_is_posted() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        sender.answers.lock().unwrap().push_back(503);
+        let state = test_state(
+            &["--dedup-window-secs", "3600"],
+            vec![integration("i1")],
+            sender.clone(),
+        );
+        let body = pubsub_envelope(SAMPLE_UPTIME_ALERT, "m1");
+
+        let first = post_alert(&state, "i1", &body).await;
+        assert_eq!(first.status(), StatusCode::BadGateway);
+        let second = post_alert(&state, "i1", &body).await;
+        assert_eq!(second.status(), StatusCode::Ok);
+        assert_eq!(sender.posts.lock().unwrap().len(), 2);
+
+        post_alert(&state, "i1", &body).await;
+        assert_eq!(sender.posts.lock().unwrap().len(), 2);
+    }
+
+    /// Stands in for a serve appending a dead letter while a replay posts.
+    struct AppendingSender {
+        path: String,
+        inner: RecordingSender,
+    }
+
+    impl OutboundSender for AppendingSender {
+        fn post<'a>(
+            &'a self,
+            url: &'a str,
+            auth_token: Option<&'a Secret>,
+            body: &'a serde_json::Value,
+        ) -> PostFuture<'a> {
+            append_json_line(&self.path, &dead_letter("late")).unwrap();
+            self.inner.post(url, auth_token, body)
+        }
+    }
+
+    fn dead_letter(text: &str) -> DeadLetter {
+        DeadLetter {
+            timestamp: 1,
+            install_id: "i1".into(),
+            severity: None,
+            policy_name: None,
+            body: json!({ "content": text }),
+        }
+    }
+
+    #[async_std::test]
+    async fn replay_keeps_failures_and_letters_appended_meanwhile() {
+        let path = temp_path("dead.jsonl");
+        append_json_line(&path, &dead_letter("fails")).unwrap();
+        append_json_line(&path, &dead_letter("goes through")).unwrap();
+        let mut store = FileStore::new(&temp_path("db.json"));
+        store.twist_integrations = vec![integration("i1")];
+        let sender = AppendingSender {
+            path: path.clone(),
+            inner: RecordingSender::default(),
+        };
+        sender.inner.answers.lock().unwrap().push_back(500);
+
+        let (delivered, remaining) = replay_dead_letters(&store, &sender, &path, false)
+            .await
+            .unwrap();
+        assert_eq!((delivered, remaining), (1, 1));
+
+        let contents: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<DeadLetter>(line).unwrap().body["content"].to_string()
+            })
+            .collect();
+        assert_eq!(contents, ["\"late\"", "\"late\"", "\"fails\""]);
+        let dir = std::path::Path::new(&path).parent().unwrap();
+        let name = std::path::Path::new(&path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        assert!(!std::fs::read_dir(dir).unwrap().any(|entry| entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&format!("{}.replaying", name))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn uptime_alert(policy: &str, incident: &str, severity: Option<&str>) -> serde_json::Value {
+        json!({
+            "incident": {
+                "policy_name": policy,
+                "url": format!("https://console.cloud.google.com/monitoring/alerting/incidents/{}", incident),
+                "summary": format!("{} is failing.", incident),
+                "state": "open",
+                "severity": severity,
+            }
+        })
+    }
+
+    #[async_std::test]
+    async fn combined_batches_post_the_new_alerts_beside_a_repeat() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let state = test_state(
+            &["--dedup-window-secs", "3600", "--batch-mode", "combined"],
+            vec![integration("i1")],
+            sender.clone(),
+        );
+        let a = uptime_alert("api uptime", "a", None);
+        let b = uptime_alert("api uptime", "b", None);
+        post_alert(&state, "i1", &a.to_string()).await;
+
+        let res = post_alert(&state, "i1", &json!([a, b]).to_string()).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let posts = sender.posts.lock().unwrap();
+        assert_eq!(posts.len(), 2);
+        let content = posts[1].1.to_string();
+        assert!(content.contains("b is failing"));
+        assert!(!content.contains("a is failing"));
+    }
+
+    #[async_std::test]
+    async fn combined_batches_are_split_by_route() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let mut twist = integration("i1");
+        twist
+            .severity_routes
+            .insert("critical".into(), "https://203.0.113.8/critical".into());
+        let state = test_state(&["--batch-mode", "combined"], vec![twist], sender.clone());
+        let batch = json!([
+            uptime_alert("api uptime", "a", None),
+            uptime_alert("api uptime", "b", Some("critical")),
+            uptime_alert("db uptime", "c", None),
+        ]);
+
+        post_alert(&state, "i1", &batch.to_string()).await;
+        let posts = sender.posts.lock().unwrap();
+        let urls: Vec<&str> = posts.iter().map(|(url, _)| url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://203.0.113.7/post_data",
+                "https://203.0.113.8/critical"
+            ]
+        );
+        assert!(posts[0]
+            .1
+            .to_string()
+            .contains("2 incidents in this delivery"));
+        assert!(posts[1].1.to_string().contains("b is failing"));
+    }
+
+    #[async_std::test]
+    async fn grouped_batches_post_the_new_alerts_of_a_policy() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let state = test_state(
+            &["--dedup-window-secs", "3600", "--group-batches"],
+            vec![integration("i1")],
+            sender.clone(),
+        );
+        let a = uptime_alert("api uptime", "a", None);
+        post_alert(&state, "i1", &a.to_string()).await;
+
+        let batch = json!([
+            a,
+            uptime_alert("api uptime", "b", None),
+            uptime_alert("api uptime", "c", None),
+        ]);
+        post_alert(&state, "i1", &batch.to_string()).await;
+        let posts = sender.posts.lock().unwrap();
+        assert_eq!(posts.len(), 2);
+        let content = posts[1].1.to_string();
+        assert!(content.contains("2 incidents of api uptime"));
+        assert!(!content.contains("a is failing"));
+    }
+
+    #[async_std::test]
+    async fn grouped_batches_route_each_severity() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let mut twist = integration("i1");
+        twist
+            .severity_routes
+            .insert("critical".into(), "https://203.0.113.8/critical".into());
+        let state = test_state(&["--group-batches"], vec![twist], sender.clone());
+        let batch = json!([
+            uptime_alert("api uptime", "a", None),
+            uptime_alert("api uptime", "b", Some("critical")),
+        ]);
+
+        post_alert(&state, "i1", &batch.to_string()).await;
+        let posts = sender.posts.lock().unwrap();
+        let urls: Vec<&str> = posts.iter().map(|(url, _)| url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://203.0.113.7/post_data",
+                "https://203.0.113.8/critical"
+            ]
+        );
+    }
+
+    #[test]
+    fn a_store_is_served_while_its_pidfile_names_a_live_process() {
+        let db = temp_path("served.json");
+        let none = None;
+        let args = StoreArgs::new(&db, &none, &none, &none);
+        let pidfile = args.pidfile().unwrap();
+        assert_eq!(pidfile, format!("{}.pid", db));
+        assert_eq!(args.served_by(), None);
+
+        std::fs::write(&pidfile, format!("{}\n", std::process::id())).unwrap();
+        assert_eq!(args.served_by(), Some(std::process::id()));
+        std::fs::write(&pidfile, format!("{}\n", u32::MAX)).unwrap();
+        assert_eq!(args.served_by(), None);
+        std::fs::remove_file(&pidfile).unwrap();
+
+        let sqlite = Some(format!("sqlite://{}", temp_path("served.sqlite")));
+        assert_eq!(StoreArgs::new(&db, &sqlite, &none, &none).pidfile(), None);
+    }
+
+    #[test]
+    fn redirects_are_held_to_the_target_checks() {
+        let allowed: Vec<Cidr> = vec!["10.1.0.0/16".parse().unwrap()];
+        let check = |url: &str| check_target_url(&reqwest::Url::parse(url).unwrap(), &allowed);
+
+        assert!(check("https://203.0.113.7/post_data").is_ok());
+        assert!(check("https://twist.example.com/post_data").is_ok());
+        assert!(check("https://10.1.2.3/post_data").is_ok());
+        assert!(check("http://203.0.113.7/post_data").is_err());
+        assert!(check("https://10.2.0.1/post_data").is_err());
+        assert!(check("https://169.254.169.254/latest").is_err());
+        assert!(check("https://[::1]/post_data").is_err());
+        assert!(check("https://[::ffff:127.0.0.1]/post_data").is_err());
+    }
+
+    #[async_std::test]
+    async fn resolved_addresses_are_checked() {
+        let loopback: Vec<Cidr> = vec!["127.0.0.0/8".parse().unwrap()];
+        assert!(resolve_target("127.0.0.1", 0, &[]).await.is_err());
+        assert!(resolve_target("localhost", 0, &[]).await.is_err());
+        let addrs = resolve_target("127.0.0.1", 0, &loopback).await.unwrap();
+        assert_eq!(addrs, ["127.0.0.1:0".parse().unwrap()]);
+        assert!(resolve_target("203.0.113.7", 0, &[]).await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn admin_routes_to_internal_addresses_are_rejected() {
+        let state = test_state(
+            &["--admin-token", ADMIN_TOKEN],
+            vec![integration("i1")],
+            Default::default(),
+        );
+        let severity = json!({ "severity_routes": { "critical": "https://127.0.0.1/critical" } });
+        let res = update_settings(&state, "i1", severity).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let policy = json!({
+            "policy_routes": [{ "policy": "^db-", "post_data_url": "https://10.0.0.5/db" }],
+        });
+        let res = update_settings(&state, "i1", policy).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let plain = json!({ "severity_routes": { "critical": "http://203.0.113.8/critical" } });
+        let res = update_settings(&state, "i1", plain).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        let public = json!({ "severity_routes": { "critical": "https://203.0.113.8/critical" } });
+        let res = update_settings(&state, "i1", public).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let stored = state
+            .store
+            .read()
+            .await
+            .find_twist_thread("i1".into())
+            .unwrap();
+        assert!(stored.policy_routes.is_empty());
+        assert_eq!(stored.severity_routes.len(), 1);
+    }
+
+    #[test]
+    fn incident_times_render_as_iso_utc() {
+        assert_eq!(format_utc(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+
+        let render = RenderOptions::from_serve(&serve_opts(&[])).unwrap();
+        let mut alert = uptime_alert("api uptime", "a", None);
+        alert["incident"]["started_at"] = json!(1_700_000_000);
+        alert["incident"]["ended_at"] = json!(1_700_000_600);
+        let content = reply_to_json(alert.to_string(), &render).content;
+        assert!(content.contains("Started 2023-11-14T22:13:20Z, ended 2023-11-14T22:23:20Z"));
+
+        let untimed = uptime_alert("api uptime", "a", None).to_string();
+        assert!(!reply_to_json(untimed, &render).content.contains("Started"));
+    }
+
+    #[async_std::test]
+    async fn only_new_integrations_count_as_registered() {
+        let state = test_state(&[], vec![], Default::default());
+        let url = tide::http::Url::parse_with_params(
+            "http://bridge.example.com/twist/on_configure",
+            &[
+                ("install_id", "i1"),
+                ("post_data_url", THREAD_URL),
+                ("user_id", "1"),
+                ("user_name", "tester"),
+            ],
+        )
+        .unwrap();
+        let path = format!("{}?{}", url.path(), url.query().unwrap());
+
+        for _ in 0..2 {
+            let res = send(&state, tide::http::Method::Get, &path, "").await;
+            assert_eq!(res.status(), StatusCode::Ok);
+        }
+        let registered = &state.metrics.integrations_registered;
+        assert_eq!(registered.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        let mut sqlite = sqlite_store();
+        assert_eq!(sqlite.register_twist_thread(configure("i1")), Ok(true));
+        assert_eq!(sqlite.register_twist_thread(configure("i1")), Ok(false));
+    }
+
+    #[test]
+    fn atomic_writes_replace_the_file_whole() {
+        let path = temp_path("atomic.json");
+        std::fs::write(&path, "old").unwrap();
+        write_atomically(&path, "new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert!(std::fs::metadata(format!("{}.tmp", path)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn shutdown_waits_for_accepted_requests() {
+        use signal_hook::consts::SIGUSR1;
+        use std::sync::atomic::Ordering;
+
+        let delay = std::time::Duration::from_millis(300);
+        let mut state = test_state(
+            &["--drain-secs", "0", "--shutdown-grace-secs", "5"],
+            vec![integration("i1")],
+            Default::default(),
+        );
+        state.sender = std::sync::Arc::new(SlowSender { delay });
+        // SIGUSR1, as a handler for SIGTERM would outlive the test in this process
+        let signals = signal_hook::iterator::Signals::new([SIGUSR1]).unwrap();
+        let drained = {
+            let state = state.clone();
+            std::thread::spawn(move || drain_after_signal(&state, signals))
+        };
+
+        let accepted = {
+            let state = state.clone();
+            async_std::task::spawn(
+                async move { post_alert(&state, "i1", SAMPLE_UPTIME_ALERT).await },
+            )
+        };
+        while state.busy.load(Ordering::SeqCst) == 0 {
+            async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let started = std::time::Instant::now();
+        signal_hook::low_level::raise(SIGUSR1).unwrap();
+        async_std::task::sleep(delay / 3).await;
+        assert!(!drained.is_finished());
+
+        assert_eq!(accepted.await.status(), StatusCode::Ok);
+        assert_eq!(drained.join().unwrap(), Some(SIGUSR1));
+        assert!(started.elapsed() < delay * 3);
+        assert_eq!(state.busy.load(Ordering::SeqCst), 0);
+        let refused = post_alert(&state, "i1", SAMPLE_UPTIME_ALERT).await;
+        assert_eq!(refused.status(), StatusCode::ServiceUnavailable);
+    }
+
+    fn default_render() -> RenderOptions {
+        RenderOptions::from_serve(&serve_opts(&[])).unwrap()
+    }
+
+    #[test]
+    fn log_alerts_list_each_affected_resource() {
+        let alert = json!({
+            "incident": {
+                "documentation": { "content": "Errors.", "mime_type": "text/markdown" },
+                "policy_name": "api errors",
+                "resources": [
+                    { "type": "k8s_container", "labels": { "container_name": "api" } },
+                    { "type": "cloud_run_revision", "labels": { "service_name": "worker" } },
+                ],
+                "url": "https://console.cloud.google.com/monitoring/alerting/incidents/0.def",
+            }
+        });
+        let content = reply_to_json(alert.to_string(), &default_render()).content;
+        assert!(content.starts_with("🚨 api errors on api "));
+        assert!(content
+            .contains("Affected resources:\n- k8s_container api\n- cloud_run_revision worker"));
+
+        let single = reply_to_json(SAMPLE_LOG_ALERT.to_string(), &default_render()).content;
+        assert!(single.starts_with("🚨 api errors on api "));
+        assert!(!single.contains("Affected resources"));
+    }
+
+    #[async_std::test]
+    async fn alerts_are_posted_through_the_sender() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let state = test_state(&[], vec![integration("i1")], sender.clone());
+
+        let res = post_alert(&state, "i1", SAMPLE_UPTIME_ALERT).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let posts = sender.posts.lock().unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].0, "https://203.0.113.7/post_data");
+        let content = posts[0].1["content"].as_str().unwrap();
+        assert!(content.contains("An uptime check on api.example.com is failing."));
+    }
+
+    #[async_std::test]
+    async fn uninstall_without_an_install_id_is_rejected() {
+        let state = test_state(&[], vec![integration("i1")], Default::default());
+        let event = json!({
+            "event_type": "uninstall",
+            "user_id": "1",
+            "user_name": "tester",
+            "install_id": null,
+        });
+
+        let mut res = post_outgoing(&state, event).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "uninstall event missing install_id"
+        );
+        let store = state.store.read().await;
+        assert!(store.find_twist_thread("i1".into()).is_some());
+    }
+
+    /// Answers every post after `delay`, like a slow twist.
+    struct SlowSender {
+        delay: std::time::Duration,
+    }
+
+    impl OutboundSender for SlowSender {
+        fn post<'a>(
+            &'a self,
+            _url: &'a str,
+            _auth_token: Option<&'a Secret>,
+            _body: &'a serde_json::Value,
+        ) -> PostFuture<'a> {
+            Box::pin(async move {
+                async_std::task::sleep(self.delay).await;
+                Ok(200)
+            })
+        }
+    }
+
+    #[async_std::test]
+    async fn a_slow_twist_does_not_serialize_webhooks() {
+        let delay = std::time::Duration::from_millis(300);
+        let mut state = test_state(&[], vec![integration("i1")], Default::default());
+        state.sender = std::sync::Arc::new(SlowSender { delay });
+
+        let started = std::time::Instant::now();
+        let requests: Vec<_> = (0..5)
+            .map(|n| {
+                let state = state.clone();
+                async_std::task::spawn(async move {
+                    let alert = uptime_alert("api uptime", &n.to_string(), None);
+                    post_alert(&state, "i1", &alert.to_string()).await.status()
+                })
+            })
+            .collect();
+        for request in requests {
+            assert_eq!(request.await, StatusCode::Ok);
+        }
+        assert!(started.elapsed() < delay * 3);
+    }
+
+    #[async_std::test]
+    async fn server_errors_are_retried_and_client_errors_are_not() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        sender.answers.lock().unwrap().extend([503, 502, 200, 404]);
+        let state = test_state(
+            &["--max-retries", "2", "--retry-base-ms", "1"],
+            vec![integration("i1")],
+            sender.clone(),
+        );
+
+        let res = post_alert(&state, "i1", SAMPLE_UPTIME_ALERT).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(sender.posts.lock().unwrap().len(), 3);
+
+        let res = post_alert(&state, "i1", SAMPLE_LOG_ALERT).await;
+        assert_eq!(res.status(), StatusCode::BadGateway);
+        assert_eq!(sender.posts.lock().unwrap().len(), 4);
+
+        assert_eq!(Delivery::classify(&Ok(429)), Delivery::Retryable);
+        assert_eq!(
+            Delivery::classify(&Err("connection reset".into())),
+            Delivery::Retryable
+        );
+        assert_eq!(Delivery::classify(&Ok(400)), Delivery::Rejected);
+    }
+
+    #[test]
+    fn a_truncated_store_is_set_aside() {
+        let path = temp_path("truncated.json");
+        let mut file = FileStore::new(&path);
+        file.create_if_missing = true;
+        file.register_twist_thread(configure("i1")).unwrap();
+        assert!(std::fs::metadata(format!("{}.tmp", path)).is_err());
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, &saved[..saved.len() / 2]).unwrap();
+
+        file.load();
+        assert!(file.list_twist_threads().is_empty());
+        assert!(std::fs::metadata(&path).is_err());
+        let backup = format!("{}.corrupt-", path);
+        let dir = std::path::Path::new(&path).parent().unwrap();
+        let backups: Vec<std::path::PathBuf> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|found| found.to_string_lossy().starts_with(&backup))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&backups[0]).unwrap(),
+            &saved[..saved.len() / 2]
+        );
+        std::fs::remove_file(&backups[0]).unwrap();
+
+        std::fs::write(&path, "").unwrap();
+        file.load();
+        assert!(file.list_twist_threads().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pubsub_pushes_are_unwrapped() {
+        let push = json!({
+            "message": {
+                "data": "eyJpbmNpZGVudCI6IHsicG9saWN5X25hbWUiOiAiYXBpIHVwdGltZSIsICJ1cmwiOiAiaHR0cHM6Ly9jb25zb2xlLmNsb3VkLmdvb2dsZS5jb20vbW9uaXRvcmluZy9hbGVydGluZy9pbmNpZGVudHMvMC5hYmMiLCAic3VtbWFyeSI6ICJBbiB1cHRpbWUgY2hlY2sgb24gYXBpLmV4YW1wbGUuY29tIGlzIGZhaWxpbmcuIiwgInN0YXRlIjogIm9wZW4ifX0=",
+                "attributes": { "install": "i2" },
+                "messageId": "m1",
+            },
+            "subscription": "projects/example/subscriptions/twist",
+        });
+        let pushed = reply_to_json(push.to_string(), &default_render());
+        let direct = reply_to_json(SAMPLE_UPTIME_ALERT.to_string(), &default_render());
+        assert!(matches!(
+            pushed.payload,
+            Some(GoogleWebhookPayload::GoogleUptimeAlert(_))
+        ));
+        assert_eq!(pushed.content, direct.content);
+        assert!(direct.pubsub.is_none());
+        let message = pushed.pubsub.unwrap();
+        assert_eq!(message.attributes["install"], "i2");
+        assert_eq!(message.message_id.as_deref(), Some("m1"));
+    }
+
+    #[async_std::test]
+    async fn store_reads_share_the_lock() {
+        let state = test_state(
+            &["--store-lock-timeout", "100"],
+            vec![integration("i1")],
+            Default::default(),
+        );
+        let held = state.store.read().await;
+
+        let lookups: Vec<_> = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                async_std::task::spawn(async move {
+                    let store = lock_store(&state).await?;
+                    store.find_twist_thread("i1".into())
+                })
+            })
+            .collect();
+        for lookup in lookups {
+            assert!(lookup.await.is_some());
+        }
+        let write =
+            async_std::future::timeout(std::time::Duration::from_millis(100), state.store.write());
+        assert!(write.await.is_err());
+
+        drop(held);
+        let res = post_alert(&state, "i1", SAMPLE_UPTIME_ALERT).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn top_level_arrays_are_unwrapped_or_batched() {
+        let single = format!("[{}]", SAMPLE_UPTIME_ALERT);
+        let replies = replies_to_json(single, &default_render());
+        assert_eq!(replies.len(), 1);
+        assert!(matches!(
+            replies[0].payload,
+            Some(GoogleWebhookPayload::GoogleUptimeAlert(_))
+        ));
+
+        let batch = format!("[{}, {}]", SAMPLE_UPTIME_ALERT, SAMPLE_LOG_ALERT);
+        let replies = replies_to_json(batch, &default_render());
+        assert_eq!(replies.len(), 2);
+        assert!(matches!(
+            replies[1].payload,
+            Some(GoogleWebhookPayload::GoogleLogAlert(_))
+        ));
+        assert!(replies.iter().all(|reply| reply.parse_error.is_none()));
+    }
+
+    #[async_std::test]
+    async fn an_open_and_its_resolution_post_to_the_same_thread() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let state = test_state(
+            &["--dedup-window-secs", "3600"],
+            vec![integration("i1")],
+            sender.clone(),
+        );
+        let open = uptime_alert("api uptime", "a", None);
+        let mut closed = open.clone();
+        closed["incident"]["state"] = json!("closed");
+
+        post_alert(&state, "i1", &open.to_string()).await;
+        post_alert(&state, "i1", &closed.to_string()).await;
+        let posts = sender.posts.lock().unwrap();
+        assert_eq!(posts.len(), 2);
+        assert_eq!(posts[0].0, posts[1].0);
+        assert!(posts[1].1["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("✅ api uptime"));
+    }
+
+    #[test]
+    fn repeats_are_suppressed_within_the_window_only() {
+        let mut dedup = Deduplicator::new(60, DedupWindowKind::Sliding);
+        assert!(dedup.admit("i1/a", 1_000));
+        assert!(!dedup.admit("i1/a", 1_059));
+        assert!(dedup.admit("i1/b", 1_059));
+        assert!(dedup.admit("i1/a", 1_060));
+
+        // entries out of the window are pruned on the next admit
+        assert!(dedup.admit("i1/c", 1_200));
+        assert_eq!(dedup.last_posted.len(), 1);
+
+        let mut disabled = Deduplicator::new(0, DedupWindowKind::Sliding);
+        assert!(disabled.admit("i1/a", 1_000));
+        assert!(disabled.admit("i1/a", 1_000));
+        assert!(disabled.last_posted.is_empty());
+    }
+
+    #[test]
+    fn print_reply_reads_stdin_without_a_file() {
+        let mut stdin = SAMPLE_UPTIME_ALERT.as_bytes();
+        let inputs = read_inputs(&[], &mut stdin).unwrap();
+        assert_eq!(inputs, [("-".to_string(), SAMPLE_UPTIME_ALERT.to_string())]);
+
+        let path = temp_path("alert.json");
+        std::fs::write(&path, SAMPLE_LOG_ALERT).unwrap();
+        let mut unread = "ignored".as_bytes();
+        let inputs = read_inputs(&[path.clone()], &mut unread).unwrap();
+        assert_eq!(inputs, [(path.clone(), SAMPLE_LOG_ALERT.to_string())]);
+        assert_eq!(unread, b"ignored");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn each_payload_kind_parses_into_its_own_variant() {
+        let parse = |json: &str| serde_json::from_str::<GoogleWebhookPayload>(json).unwrap();
+        assert!(matches!(
+            parse(SAMPLE_UPTIME_ALERT),
+            GoogleWebhookPayload::GoogleUptimeAlert(_)
+        ));
+        assert!(matches!(
+            parse(SAMPLE_LOG_ALERT),
+            GoogleWebhookPayload::GoogleLogAlert(_)
+        ));
+        assert!(matches!(
+            parse(SAMPLE_BUDGET_ALERT),
+            GoogleWebhookPayload::GoogleBudgetAlert(_)
+        ));
+
+        let content = reply_to_json(SAMPLE_BUDGET_ALERT.to_string(), &default_render()).content;
+        assert_eq!(
+            content,
+            "💰 Budget example monthly exceeded 90% (912.40 USD of 1000.00 USD)"
+        );
+    }
+
+    #[test]
+    fn templates_fill_in_the_alert_fields() {
+        let path = temp_path("template.md");
+        std::fs::write(&path, "{state_emoji} {policy_name} on {service}, cc @ops").unwrap();
+        let render =
+            RenderOptions::from_serve(&serve_opts(&["--template-file", path.as_str()])).unwrap();
+
+        let log = reply_to_json(SAMPLE_LOG_ALERT.to_string(), &render).content;
+        assert_eq!(log, "🚨 api errors on api, cc @ops");
+        std::fs::write(&path, "{state_emoji} {policy_name}: {summary}").unwrap();
+        let render =
+            RenderOptions::from_serve(&serve_opts(&["--template-file", path.as_str()])).unwrap();
+        let uptime = reply_to_json(SAMPLE_UPTIME_ALERT.to_string(), &render).content;
+        assert_eq!(
+            uptime,
+            "🚨 api uptime: An uptime check on api.example.com is failing."
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let builtin = reply_to_json(SAMPLE_UPTIME_ALERT.to_string(), &default_render()).content;
+        assert!(builtin.starts_with("🚨 api uptime [incident]("));
+    }
+
+    #[async_std::test]
+    async fn posts_to_a_hung_twist_time_out() {
+        // connections queue up in the backlog and are never answered
+        let hung = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}/post_data", hung.local_addr().unwrap());
+        let timeout = std::time::Duration::from_secs(1);
+        let loopback: Vec<Cidr> = vec!["127.0.0.0/8".parse().unwrap()];
+        let sender = ReqwestSender::new(false, &loopback, 1).unwrap();
+
+        let started = std::time::Instant::now();
+        let outcome = sender.post(&url, None, &json!({ "content": "hi" })).await;
+        assert!(started.elapsed() < timeout * 3);
+        assert!(outcome.as_ref().unwrap_err().starts_with("timed out"));
+        assert_eq!(Delivery::classify(&outcome), Delivery::Retryable);
+    }
+
+    #[test]
+    fn a_repeated_configure_updates_the_stored_integration() {
+        let path = temp_path("reconfigure.json");
+        let mut file = FileStore::new(&path);
+        file.register_twist_thread(configure("i1")).unwrap();
+        file.update_twist_thread("i1", &mut |twist| twist.include_chart = true);
+        let mut again = configure("i1");
+        again.user_name = "renamed".into();
+        file.register_twist_thread(again).unwrap();
+
+        let twists = file.list_twist_threads();
+        assert_eq!(twists.len(), 1);
+        assert_eq!(twists[0].secret_id, "i1");
+        assert_eq!(twists[0].configuration.user_name, "renamed");
+        assert!(twists[0].include_chart);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn every_twist_event_type_is_answered() {
+        let state = test_state(&[], vec![integration("i1")], Default::default());
+        let event = |event_type: &str| {
+            json!({
+                "event_type": event_type,
+                "user_id": "1",
+                "user_name": "tester",
+                "content": "hello",
+                "install_id": "i1",
+            })
+        };
+
+        for event_type in ["ping", "message", "thread", "comment"] {
+            let mut res = post_outgoing(&state, event(event_type)).await;
+            assert_eq!(res.status(), StatusCode::Ok, "{}", event_type);
+            let body: serde_json::Value = res.body_json().await.unwrap();
+            assert!(body["content"].is_string(), "{}", event_type);
+        }
+        let res = post_outgoing(&state, event("reaction")).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        let res = post_outgoing(&state, event("uninstall")).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let store = state.store.read().await;
+        assert!(store.find_twist_thread("i1".into()).is_none());
+    }
+
+    #[test]
+    fn log_alerts_list_the_resource_labels() {
+        let mut alert: serde_json::Value = serde_json::from_str(SAMPLE_LOG_ALERT).unwrap();
+        alert["incident"]["resource"] = json!({
+            "type": "k8s_container",
+            "labels": {
+                "project_id": "example",
+                "location": "europe-west1-b",
+                "cluster_name": "prod",
+                "namespace_name": "api",
+                "pod_name": "api-7d9f8-x2k4q",
+                "container_name": "api",
+            }
+        });
+        let content = reply_to_json(alert.to_string(), &default_render()).content;
+        assert!(content.contains(
+            "`cluster_name=prod, container_name=api, location=europe-west1-b, \
+             namespace_name=api, pod_name=api-7d9f8-x2k4q, project_id=example`"
+        ));
+
+        alert["incident"]["resource"]["labels"] = json!({ "project_id": "example" });
+        let content = reply_to_json(alert.to_string(), &default_render()).content;
+        assert!(content.starts_with("🚨 api errors on unknown "));
+        assert!(content.contains("`project_id=example`"));
+    }
+
+    async fn configure_via_twist(state: &State, install_id: &str) -> tide::http::Response {
+        let url = tide::http::Url::parse_with_params(
+            "http://bridge.example.com/twist/on_configure",
+            &[
+                ("install_id", install_id),
+                ("post_data_url", THREAD_URL),
+                ("user_id", "1"),
+                ("user_name", "tester"),
+            ],
+        )
+        .unwrap();
+        let path = format!("{}?{}", url.path(), url.query().unwrap());
+        send(state, tide::http::Method::Get, &path, "").await
+    }
+
+    #[async_std::test]
+    async fn the_install_message_is_configurable_and_optional() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let state = test_state(
+            &["--install-message", "GCP alerts land here."],
+            vec![],
+            sender.clone(),
+        );
+        let mut res = configure_via_twist(&state, "i1").await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(res.body_string().await.unwrap().contains("A hello message"));
+        let posts = sender.posts.lock().unwrap().clone();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].1["content"], "GCP alerts land here.");
+
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let state = test_state(&["--no-install-message"], vec![], sender.clone());
+        let mut res = configure_via_twist(&state, "i1").await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(!res.body_string().await.unwrap().contains("A hello message"));
+        assert!(sender.posts.lock().unwrap().is_empty());
+        let store = state.store.read().await;
+        assert!(store.find_twist_thread("i1".into()).is_some());
+    }
+
+    #[test]
+    fn json_logs_are_parseable_lines() {
+        let line = JsonLogger::line(
+            &log::Record::builder()
+                .args(format_args!("skipped alert for {}, muted", "i1"))
+                .level(log::Level::Info)
+                .target("bridge")
+                .key_values(&("install_id", "i1"))
+                .build(),
+        );
+        assert!(!line.contains('\n'));
+        let fields: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(fields["severity"], "INFO");
+        assert_eq!(fields["message"], "skipped alert for i1, muted");
+        assert_eq!(fields["install_id"], "i1");
+        assert!(fields["time"].is_u64());
+    }
+
+    #[async_std::test]
+    async fn malformed_install_ids_are_rejected() {
+        let state = test_state(&[], vec![integration("i1")], Default::default());
+        for install_id in ["", "../db", "a b", "i1?x=1"] {
+            let res = configure_via_twist(&state, install_id).await;
+            assert_eq!(res.status(), StatusCode::BadRequest, "{:?}", install_id);
+        }
+        assert_eq!(state.store.read().await.list_twist_threads().len(), 1);
+
+        let res = post_alert(&state, "i1.json", SAMPLE_UPTIME_ALERT).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        assert!(is_valid_install_id("Ab-1_2"));
+    }
+
+    #[async_std::test]
+    async fn healthz_fails_when_the_store_is_unreadable() {
+        let state = test_state(&[], vec![], Default::default());
+        let mut res = send(&state, tide::http::Method::Get, "/healthz", "").await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["store"], "ok");
+
+        // integrations were loaded from a file that has since gone
+        let state = test_state(&[], vec![integration("i1")], Default::default());
+        let mut res = send(&state, tide::http::Method::Get, "/healthz", "").await;
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["status"], "failed");
+        assert!(body["store"].as_str().unwrap().contains("is unreadable"));
+    }
+
+    #[async_std::test]
+    async fn admin_routes_need_the_whole_token() {
+        let state = test_state(
+            &["--admin-token", "admin-secret-2"],
+            vec![integration("i1")],
+            Default::default(),
+        );
+        let res = update_settings(&state, "i1", json!({ "notify_all_on_open": true })).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let state = test_state(
+            &["--admin-token", ADMIN_TOKEN],
+            vec![integration("i1")],
+            Default::default(),
+        );
+        let res = update_settings(&state, "i1", json!({ "notify_all_on_open": true })).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn posts_to_an_internal_address_are_refused() {
+        let sender = ReqwestSender::new(false, &[], 1).unwrap();
+        let outcome = sender
+            .post(
+                "https://127.0.0.1/post_data",
+                None,
+                &json!({ "content": "hi" }),
+            )
+            .await;
+        assert_eq!(outcome.unwrap_err(), "127.0.0.1 is an internal address");
+    }
+
+    #[async_std::test]
+    async fn mutes_are_capped_at_a_year() {
+        let state = test_state(
+            &["--admin-token", ADMIN_TOKEN],
+            vec![integration("i1")],
+            Default::default(),
+        );
+        let mute = |secs: u64| json!({ "duration_secs": secs }).to_string();
+        let path = "/admin/integrations/i1/mute";
+        let res = send(&state, tide::http::Method::Post, path, &mute(u64::MAX)).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let res = send(&state, tide::http::Method::Post, path, &mute(MAX_MUTE_SECS)).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        assert_eq!(format_utc(u64::MAX), u64::MAX.to_string());
+        assert!(format_utc(unix_now() + MAX_MUTE_SECS).ends_with('Z'));
+    }
+
+    #[async_std::test]
+    async fn redaction_patterns_are_checked_as_they_are_set() {
+        let sender = std::sync::Arc::new(RecordingSender::default());
+        let state = test_state(
+            &["--admin-token", ADMIN_TOKEN],
+            vec![integration("i1")],
+            sender.clone(),
+        );
+        let res = update_settings(&state, "i1", json!({ "redact_patterns": ["db-("] })).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let res = update_settings(&state, "i1", json!({ "redact_patterns": ["api\\.\\w+"] })).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let alert = uptime_alert("api uptime", "api.example.com", None);
+        post_alert(&state, "i1", &alert.to_string()).await;
+        let posts = sender.posts.lock().unwrap();
+        let content = posts[0].1["content"].as_str().unwrap();
+        assert!(content.contains("[redacted]"), "{}", content);
+        assert!(!content.contains("api.example"), "{}", content);
+    }
 }